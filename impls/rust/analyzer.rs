@@ -0,0 +1,317 @@
+// Lexical addressing. When a fn* is created its body is analyzed once:
+// every reference to a local (a param, or a let* or catch* binding,
+// within the function) becomes Local(name, depth, slot), which
+// eval fetches from the frame depth levels up without a name lookup.
+// Inner fn* forms are analyzed along with the body and marked as such.
+// Everything else, including globals, stays a symbol.
+//
+// Frames are laid out as env_bind and let* fill them: the names in
+// order, a repeated name reusing its slot. Forms that a known macro
+// will rewrite are left alone, as are names def!'d into a frame; should
+// anything still bind a name the analyzer didn't expect (a def! from a
+// macro, env-set!), env_get_local finds it in the frames on the way to
+// the slot, or sees that the slot no longer holds the name and looks it
+// up.
+
+use std::rc::Rc;
+
+use crate::env::{env_get, pattern_names, Env};
+use crate::types::MalVal::{Hash, List, Local, MalFunc, Nil, Sym, Vector};
+use crate::types::{MalVal, Symbol};
+
+// The names bound in one frame, in slot order, and the names def!'d into
+// it at run time
+#[derive(Default)]
+struct Frame {
+    slots: Vec<Symbol>,
+    defs: Vec<Symbol>,
+}
+
+impl Frame {
+    fn bind(&mut self, name: &Symbol) {
+        if !self.slots.contains(name) {
+            self.slots.push(name.clone());
+        }
+    }
+}
+
+struct Analyzer<'a> {
+    // outermost first
+    frames: Vec<Frame>,
+    env: &'a Env,
+}
+
+thread_local! {
+    // The metadata of an analyzed fn* form: made only here, so that no
+    // metadata a user attaches to a form is taken for it
+    static ANALYZED: Rc<MalVal> = Rc::new(Nil);
+}
+
+fn analyzed(l: Vec<MalVal>) -> MalVal {
+    List(Rc::new(l), ANALYZED.with(Rc::clone))
+}
+
+// Whether the metadata of a fn* form says its body has been analyzed
+pub fn is_analyzed(meta: &Rc<MalVal>) -> bool {
+    ANALYZED.with(|m| Rc::ptr_eq(m, meta))
+}
+
+// Analyze a fn* form created in env. Only the frames laid out here are
+// addressed: a frame that already exists may still gain bindings (e.g.
+// a let* binding a name after a closure over it is created).
+pub fn analyze_fn(l: &[MalVal], env: &Env) -> MalVal {
+    Analyzer {
+        frames: vec![],
+        env,
+    }
+    .fn_form(l)
+}
+
+// The form with its resolved locals turned back into symbols, for a
+// macro the analyzer didn't know about
+pub fn unresolve(ast: &MalVal) -> MalVal {
+    match ast {
+        Local(s, ..) => Sym(s.clone()),
+        List(l, _) => list!(l.iter().map(unresolve).collect()),
+        Vector(l, meta) => Vector(Rc::new(l.iter().map(unresolve).collect()), meta.clone()),
+        Hash(hm, meta) => Hash(
+            Rc::new(hm.iter().map(|(k, v)| (k.clone(), unresolve(v))).collect()),
+            meta.clone(),
+        ),
+        _ => ast.clone(),
+    }
+}
+
+pub fn is_resolved(ast: &MalVal) -> bool {
+    match ast {
+        Local(..) => true,
+        List(l, meta) => is_analyzed(meta) || l.iter().any(is_resolved),
+        Vector(l, _) => l.iter().any(is_resolved),
+        Hash(hm, _) => hm.values().any(is_resolved),
+        _ => false,
+    }
+}
+
+// Whether the forms following fn* are the clauses of a multi-arity
+// function, e.g. ([x] x) ([x y] (+ x y)), rather than params and body
+pub fn is_multi_arity(forms: &[MalVal]) -> bool {
+    !forms.is_empty()
+        && forms.iter().all(|f| match f {
+            List(c, _) => matches!(c.first(), Some(Vector(..))),
+            _ => false,
+        })
+}
+
+fn head(l: &[MalVal]) -> Option<&str> {
+    match l.first() {
+        Some(Sym(s)) => Some(s),
+        _ => None,
+    }
+}
+
+// The names def!'d into the current frame by forms
+fn collect_defs(forms: &[MalVal], defs: &mut Vec<Symbol>) {
+    for form in forms {
+        match form {
+            List(l, _) => match head(l) {
+                Some("def!") | Some("def-dynamic") | Some("defmacro!") => {
+                    if let Some(Sym(name)) = l.get(1) {
+                        defs.push(name.clone());
+                    }
+                    collect_defs(&l[1..], defs);
+                }
+                Some("quote") | Some("quasiquote") | Some("fn*") | Some("let*") | Some("loop")
+                | Some("catch*") => {}
+                _ => collect_defs(l, defs),
+            },
+            Vector(l, _) => collect_defs(l, defs),
+            _ => {}
+        }
+    }
+}
+
+impl<'a> Analyzer<'a> {
+    fn resolve(&self, name: &Symbol) -> Option<(usize, usize)> {
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if frame.defs.contains(name) {
+                return None;
+            }
+            if let Some(slot) = frame.slots.iter().position(|s| s == name) {
+                return Some((depth, slot));
+            }
+        }
+        None
+    }
+
+    fn is_macro(&self, name: &Symbol) -> bool {
+        self.resolve(name).is_none()
+            && matches!(
+                env_get(self.env, &Sym(name.clone())),
+                Ok(MalFunc { is_macro: true, .. })
+            )
+    }
+
+    // Forms evaluated in a new frame binding the names in patterns
+    fn in_frame(&mut self, patterns: &[MalVal], forms: &[MalVal]) -> Vec<MalVal> {
+        let mut names = vec![];
+        for pattern in patterns {
+            pattern_names(pattern, &mut names);
+        }
+        let mut frame = Frame::default();
+        for name in &names {
+            frame.bind(name);
+        }
+        collect_defs(forms, &mut frame.defs);
+        self.frames.push(frame);
+        let forms = self.forms(forms);
+        self.frames.pop();
+        forms
+    }
+
+    fn forms(&mut self, forms: &[MalVal]) -> Vec<MalVal> {
+        forms.iter().map(|f| self.form(f)).collect()
+    }
+
+    fn form(&mut self, ast: &MalVal) -> MalVal {
+        match ast {
+            Sym(s) => match self.resolve(s) {
+                Some((depth, slot)) => Local(s.clone(), depth, slot),
+                None => ast.clone(),
+            },
+            List(l, _) if !l.is_empty() => self.list(l).unwrap_or_else(|| ast.clone()),
+            Vector(l, meta) => Vector(Rc::new(self.forms(l)), meta.clone()),
+            Hash(hm, meta) => Hash(
+                Rc::new(hm.iter().map(|(k, v)| (k.clone(), self.form(v))).collect()),
+                meta.clone(),
+            ),
+            _ => ast.clone(),
+        }
+    }
+
+    // None when the list is to be left as it is
+    fn list(&mut self, l: &[MalVal]) -> Option<MalVal> {
+        let res = match head(l) {
+            Some("quote") | Some("quasiquoteexpand") | Some("macroexpand") => return None,
+            Some("quasiquote") if l.len() == 2 => list![l[0].clone(), self.unquoted(&l[1])],
+            Some("def!") | Some("def-dynamic") | Some("defmacro!") if l.len() >= 3 => {
+                let (last, rest) = l.split_last().unwrap();
+                let mut res = rest.to_vec();
+                res.push(self.form(last));
+                list!(res)
+            }
+            Some("let*") | Some("loop") if l.len() >= 2 => return self.let_form(l),
+            // the vars a binding sets are named, not evaluated
+            Some("binding") if l.len() >= 2 => {
+                let binds = match l[1] {
+                    List(ref b, _) | Vector(ref b, _) => b.iter().enumerate(),
+                    _ => return None,
+                };
+                let binds = binds
+                    .map(|(i, x)| if i % 2 == 0 { x.clone() } else { self.form(x) })
+                    .collect();
+                let mut res = vec![l[0].clone(), vector!(binds)];
+                res.extend(self.forms(&l[2..]));
+                list!(res)
+            }
+            Some("fn*") if l.len() >= 2 => self.fn_form(l),
+            Some("try*") => {
+                let mut res = vec![l[0].clone()];
+                for form in &l[1..] {
+                    res.push(match form {
+                        List(c, _) if head(c) == Some("catch*") && c.len() >= 3 => {
+                            let mut catch = c[..2].to_vec();
+                            catch.extend(self.in_frame(&c[1..2], &c[2..]));
+                            list!(catch)
+                        }
+                        _ => self.form(form),
+                    });
+                }
+                list!(res)
+            }
+            Some(_) => match l[0] {
+                Sym(ref s) if self.is_macro(s) => return None,
+                // the head stays a symbol, for macros bound to locals
+                _ => {
+                    let mut res = vec![l[0].clone()];
+                    res.extend(self.forms(&l[1..]));
+                    list!(res)
+                }
+            },
+            None => list!(self.forms(l)),
+        };
+        Some(res)
+    }
+
+    // (let* (a 1 b 2) body), or a loop: the inits and body are evaluated in the new
+    // frame (a later binding can be seen by a closure in an earlier init)
+    fn let_form(&mut self, l: &[MalVal]) -> Option<MalVal> {
+        let (binds, meta) = match l[1] {
+            List(ref b, ref m) | Vector(ref b, ref m) => (b, m),
+            _ => return None,
+        };
+        let names: Vec<MalVal> = binds.iter().step_by(2).cloned().collect();
+        let mut forms: Vec<MalVal> = binds.iter().skip(1).step_by(2).cloned().collect();
+        let inits = forms.len();
+        forms.extend_from_slice(&l[2..]);
+        let mut forms = self.in_frame(&names, &forms);
+        let body = forms.split_off(inits);
+
+        let mut new_binds = vec![];
+        for (i, name) in names.into_iter().enumerate() {
+            new_binds.push(name);
+            if let Some(init) = forms.get(i) {
+                new_binds.push(init.clone());
+            }
+        }
+        let new_binds = match l[1] {
+            List(..) => List(Rc::new(new_binds), meta.clone()),
+            _ => Vector(Rc::new(new_binds), meta.clone()),
+        };
+        let mut res = vec![l[0].clone(), new_binds];
+        res.extend(body);
+        Some(list!(res))
+    }
+
+    fn fn_form(&mut self, l: &[MalVal]) -> MalVal {
+        // each clause of a multi-arity fn* has a frame of its own
+        if is_multi_arity(&l[1..]) {
+            let mut res = vec![l[0].clone()];
+            for clause in &l[1..] {
+                if let List(c, _) = clause {
+                    let params = match c[0] {
+                        Vector(ref p, _) => p.to_vec(),
+                        _ => vec![],
+                    };
+                    let mut clause = vec![c[0].clone()];
+                    clause.extend(self.in_frame(&params, &c[1..]));
+                    res.push(list!(clause));
+                }
+            }
+            return analyzed(res);
+        }
+        let params = match l[1] {
+            List(ref p, _) | Vector(ref p, _) => p.to_vec(),
+            _ => vec![],
+        };
+        let mut res = l[..2].to_vec();
+        res.extend(self.in_frame(&params, &l[2..]));
+        analyzed(res)
+    }
+
+    // Within a quasiquote only the unquoted forms are evaluated
+    fn unquoted(&mut self, ast: &MalVal) -> MalVal {
+        match ast {
+            List(l, _)
+                if l.len() == 2 && matches!(head(l), Some("unquote") | Some("splice-unquote")) =>
+            {
+                list![l[0].clone(), self.form(&l[1])]
+            }
+            List(l, _) => list!(l.iter().map(|x| self.unquoted(x)).collect()),
+            Vector(l, meta) => Vector(
+                Rc::new(l.iter().map(|x| self.unquoted(x)).collect()),
+                meta.clone(),
+            ),
+            _ => ast.clone(),
+        }
+    }
+}
@@ -1,6 +1,9 @@
-use std::fs::File;
-use std::io::Read;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -8,11 +11,22 @@ extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
+use fnv::FnvHashMap;
+
+use crate::env::{
+    core_env, current_ns, env_get, env_keys, env_new, env_new_ns, env_set, find_ns, ns_alias,
+    ns_refer, repl_env_get, set_current_ns, visible_names, Env,
+};
+use crate::printer::{edn_str, pr_seq, pr_seq_to, str_seq, str_to, write_io};
+use crate::reader::{read_diagnostics, read_edn, read_file, read_str};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, Bool, Code, Func, Hash, Int, List, Local, MalEnv, MalFunc, Nil, Str, Sym, Vector,
+};
+use crate::types::{
+    FnInfo, MalArgs, MalErr, MalRet, MalVal, Symbol, _assoc, _dissoc, atom, enter_depth, error,
+    func, hash_map,
+};
 
 macro_rules! fn_t_int_int {
     ($ret:ident, $fn:expr) => {{
@@ -38,7 +52,7 @@ macro_rules! fn_is_type {
 macro_rules! fn_str {
     ($fn:expr) => {{
         |a: MalArgs| match a[0].clone() {
-            Str(a0) => $fn(a0),
+            Str(ref a0) => $fn(a0.clone()),
             _ => error("expecting (str) arg"),
         }
     }};
@@ -46,11 +60,26 @@ macro_rules! fn_str {
 
 fn symbol(a: MalArgs) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
+        Str(ref s) => Ok(Sym(Symbol::new(s))),
         _ => error("illegal symbol call"),
     }
 }
 
+static GENSYM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn gensym_str(prefix: &str) -> String {
+    let n = GENSYM_COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{}{}", prefix, n)
+}
+
+fn gensym(a: MalArgs) -> MalRet {
+    match a.first() {
+        None => Ok(Sym(gensym_str("G__").into())),
+        Some(Str(ref p)) => Ok(Sym(gensym_str(p).into())),
+        _ => error("gensym: prefix is not Str"),
+    }
+}
+
 fn readline(a: MalArgs) -> MalRet {
     lazy_static! {
         static ref RL: Mutex<Editor<()>> = Mutex::new(Editor::<()>::new());
@@ -79,14 +108,630 @@ fn readline(a: MalArgs) -> MalRet {
     }
 }
 
-fn slurp(f: String) -> MalRet {
+fn file_contents(f: &str) -> Result<String, MalErr> {
     let mut s = String::new();
     match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
-        Ok(_) => Ok(Str(s)),
+        Ok(_) => Ok(s),
+        Err(e) => Err(ErrString(e.to_string())),
+    }
+}
+
+fn slurp(f: String) -> MalRet {
+    Ok(Str(file_contents(&f)?))
+}
+
+// (spit f content :append true) writes (str content) to the file f
+fn spit(a: MalArgs) -> MalRet {
+    let f = match (a.first(), a.get(1)) {
+        (Some(Str(f)), Some(_)) => f,
+        _ => return error("spit: expected a filename and content"),
+    };
+    let mut append = false;
+    for opt in a[2..].chunks(2) {
+        match opt {
+            [Str(k), v] if k == "\u{29e}append" => append = !matches!(v, Nil | Bool(false)),
+            _ => return error("spit: invalid option"),
+        }
+    }
+    let res = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(f)
+        .and_then(|file| {
+            let mut w = BufWriter::new(file);
+            write_io(&mut w, |w| str_to(w, &a[1..2]))?;
+            w.flush()
+        });
+    match res {
+        Ok(_) => Ok(Nil),
+        Err(e) => error(&e.to_string()),
+    }
+}
+
+// Streams the output of f to stdout
+fn print_out(f: impl FnOnce(&mut dyn std::fmt::Write) -> std::fmt::Result) -> MalRet {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match write_io(&mut out, f) {
+        Ok(_) => Ok(Nil),
         Err(e) => error(&e.to_string()),
     }
 }
 
+fn pprint_width(a: &MalArgs) -> Result<usize, MalErr> {
+    match a.get(1) {
+        None => Ok(80),
+        Some(Int(w)) if *w > 0 => Ok(*w as usize),
+        _ => Err(ErrString(
+            "pprint: width must be a positive Int".to_string(),
+        )),
+    }
+}
+
+fn pprint_str(a: MalArgs) -> MalRet {
+    Ok(Str(a[0].pprint_str(pprint_width(&a)?)))
+}
+
+fn pprint(a: MalArgs) -> MalRet {
+    let width = pprint_width(&a)?;
+    print_out(|w| {
+        a[0].pprint_to(w, width)?;
+        w.write_char('\n')
+    })
+}
+
+// JSON: objects are read as hash-maps, arrays as vectors and null as
+// nil. mal has no floating point numbers, so a number with a fraction or
+// exponent is read only when it is whole, e.g. 2.0 or 1e3.
+
+struct JsonReader<'a> {
+    s: &'a str,
+    pos: usize,
+    keywordize: bool,
+}
+
+impl<'a> JsonReader<'a> {
+    fn error<T>(&self, msg: &str) -> Result<T, MalErr> {
+        let before = &self.s[..self.pos];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        Err(ErrString(format!(
+            "json: {} at line {}, column {}",
+            msg, line, column
+        )))
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn skip_ws(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start_matches([' ', '\t', '\n', '\r']).len();
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, MalErr> {
+        match self.peek() {
+            Some(c) => self.error(&format!("expected {}, got '{}'", expected, c)),
+            None => self.error(&format!("expected {}, got EOF", expected)),
+        }
+    }
+
+    fn read(mut self) -> MalRet {
+        let v = self.value()?;
+        self.skip_ws();
+        match self.peek() {
+            None => Ok(v),
+            Some(_) => self.unexpected("end of input"),
+        }
+    }
+
+    fn value(&mut self) -> MalRet {
+        let _depth = match enter_depth() {
+            Ok(d) => d,
+            Err(_) => return self.error("stack depth exceeded"),
+        };
+        self.skip_ws();
+        match self.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Str(self.string()?)),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => {
+                let rest = &self.s[self.pos..];
+                let word = match rest.find(|c: char| !c.is_ascii_alphanumeric()) {
+                    Some(i) => &rest[..i],
+                    None => rest,
+                };
+                let v = match word {
+                    "null" => Nil,
+                    "true" => Bool(true),
+                    "false" => Bool(false),
+                    _ => return self.error(&format!("unexpected '{}'", word)),
+                };
+                self.pos += word.len();
+                Ok(v)
+            }
+            _ => self.unexpected("value"),
+        }
+    }
+
+    // Calls item for each element up to the closing bracket
+    fn elements(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<(), MalErr>,
+    ) -> Result<(), MalErr> {
+        self.pos += 1;
+        self.skip_ws();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return Ok(());
+        }
+        loop {
+            item(self)?;
+            self.skip_ws();
+            match self.peek() {
+                Some(',') => self.pos += 1,
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                _ => return self.unexpected(&format!("',' or '{}'", close)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> MalRet {
+        let mut items = vec![];
+        self.elements(']', |r| {
+            items.push(r.value()?);
+            Ok(())
+        })?;
+        Ok(vector!(items))
+    }
+
+    fn object(&mut self) -> MalRet {
+        let mut hm = FnvHashMap::default();
+        self.elements('}', |r| {
+            r.skip_ws();
+            if r.peek() != Some('"') {
+                return r.unexpected("string key");
+            }
+            let k = r.string()?;
+            r.skip_ws();
+            if r.peek() != Some(':') {
+                return r.unexpected("':'");
+            }
+            r.pos += 1;
+            let v = r.value()?;
+            if r.keywordize {
+                hm.insert(format!("\u{29e}{}", k), v);
+            } else {
+                hm.insert(k, v);
+            }
+            Ok(())
+        })?;
+        Ok(Hash(Rc::new(hm), Rc::new(Nil)))
+    }
+
+    fn number(&mut self) -> MalRet {
+        let rest = &self.s[self.pos..];
+        let digits = |s: &str| s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let mut end = usize::from(rest.starts_with('-'));
+        let int = digits(&rest[end..]);
+        if int == 0 || (int > 1 && rest[end..].starts_with('0')) {
+            return self.error("invalid number");
+        }
+        end += int;
+        let int_end = end;
+        if rest[end..].starts_with('.') {
+            end += 1;
+            let frac = digits(&rest[end..]);
+            if frac == 0 {
+                return self.error("invalid number");
+            }
+            end += frac;
+        }
+        if rest[end..].starts_with(['e', 'E']) {
+            end += 1;
+            end += usize::from(rest[end..].starts_with(['+', '-']));
+            let exp = digits(&rest[end..]);
+            if exp == 0 {
+                return self.error("invalid number");
+            }
+            end += exp;
+        }
+        let text = &rest[..end];
+        let n = if end == int_end {
+            match text.parse::<i64>() {
+                Ok(n) => n,
+                Err(_) => return self.error(&format!("integer {} out of range", text)),
+            }
+        } else {
+            match text.parse::<f64>() {
+                Ok(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => f as i64,
+                _ => {
+                    let msg = format!("cannot read {}, mal has no floating point numbers", text);
+                    return self.error(&msg);
+                }
+            }
+        };
+        self.pos += end;
+        Ok(Int(n))
+    }
+
+    fn hex4(&mut self) -> Result<u32, MalErr> {
+        let hex = self.s.get(self.pos..self.pos + 4).unwrap_or("");
+        match u32::from_str_radix(hex, 16) {
+            Ok(n) if hex.len() == 4 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                self.pos += 4;
+                Ok(n)
+            }
+            _ => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, MalErr> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => return self.error("expected '\"', got EOF"),
+            };
+            if c < ' ' {
+                return self.error("control character in string");
+            }
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let e = self.peek();
+                    self.pos += 1;
+                    match e {
+                        Some('"') => s.push('"'),
+                        Some('\\') => s.push('\\'),
+                        Some('/') => s.push('/'),
+                        Some('b') => s.push('\u{8}'),
+                        Some('f') => s.push('\u{c}'),
+                        Some('n') => s.push('\n'),
+                        Some('r') => s.push('\r'),
+                        Some('t') => s.push('\t'),
+                        Some('u') => {
+                            let mut n = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&n)
+                                && self.s[self.pos..].starts_with("\\u")
+                            {
+                                self.pos += 2;
+                                let lo = self.hex4()?;
+                                if !(0xdc00..0xe000).contains(&lo) {
+                                    return self.error("invalid unicode escape");
+                                }
+                                n = 0x10000 + ((n - 0xd800) << 10) + (lo - 0xdc00);
+                            }
+                            match char::from_u32(n) {
+                                Some(c) => s.push(c),
+                                None => return self.error("invalid unicode escape"),
+                            }
+                        }
+                        _ => {
+                            self.pos -= 1;
+                            return self.error("invalid escape in string");
+                        }
+                    }
+                }
+                _ => s.push(c),
+            }
+        }
+    }
+}
+
+struct JsonWriter<'a> {
+    w: &'a mut dyn fmt::Write,
+    pretty: bool,
+    depth: usize,
+    // why writing stopped, when the value cannot be represented
+    error: Option<String>,
+}
+
+impl<'a> JsonWriter<'a> {
+    fn new(w: &'a mut dyn fmt::Write, pretty: bool) -> JsonWriter<'a> {
+        JsonWriter {
+            w,
+            pretty,
+            depth: 0,
+            error: None,
+        }
+    }
+
+    fn string(&mut self, s: &str) -> fmt::Result {
+        let s = s.strip_prefix('\u{29e}').unwrap_or(s);
+        self.w.write_char('"')?;
+        for c in s.chars() {
+            match c {
+                '"' => self.w.write_str("\\\"")?,
+                '\\' => self.w.write_str("\\\\")?,
+                '\n' => self.w.write_str("\\n")?,
+                '\r' => self.w.write_str("\\r")?,
+                '\t' => self.w.write_str("\\t")?,
+                c if c < ' ' => write!(self.w, "\\u{:04x}", c as u32)?,
+                c => self.w.write_char(c)?,
+            }
+        }
+        self.w.write_char('"')
+    }
+
+    fn newline(&mut self) -> fmt::Result {
+        if self.pretty {
+            write!(self.w, "\n{:1$}", "", self.depth * 2)?;
+        }
+        Ok(())
+    }
+
+    // Writes open, the items (each written by f) and close
+    fn coll<T>(
+        &mut self,
+        open: char,
+        items: impl ExactSizeIterator<Item = T>,
+        mut f: impl FnMut(&mut Self, T) -> fmt::Result,
+        close: char,
+    ) -> fmt::Result {
+        self.w.write_char(open)?;
+        if items.len() > 0 {
+            self.depth += 1;
+            for (i, x) in items.enumerate() {
+                if i > 0 {
+                    self.w.write_char(',')?;
+                }
+                self.newline()?;
+                f(self, x)?;
+            }
+            self.depth -= 1;
+            self.newline()?;
+        }
+        self.w.write_char(close)
+    }
+
+    fn write(&mut self, v: &MalVal) -> fmt::Result {
+        match v {
+            Nil => self.w.write_str("null"),
+            Bool(b) => write!(self.w, "{}", b),
+            Int(i) => write!(self.w, "{}", i),
+            Str(s) => self.string(s),
+            Sym(s) | Local(s, ..) => self.string(s),
+            List(l, _) | Vector(l, _) => self.coll('[', l.iter(), |jw, x| jw.write(x), ']'),
+            Hash(hm, _) => {
+                // sorted, so that output is stable
+                let mut keys: Vec<&String> = hm.keys().collect();
+                keys.sort_by_key(|k| k.strip_prefix('\u{29e}').unwrap_or(k));
+                let sep = if self.pretty { ": " } else { ":" };
+                self.coll(
+                    '{',
+                    keys.into_iter(),
+                    |jw, k| {
+                        jw.string(k)?;
+                        jw.w.write_str(sep)?;
+                        jw.write(&hm[k])
+                    },
+                    '}',
+                )
+            }
+            Func(..) | MalFunc { .. } => self.fail("json: cannot represent a function"),
+            Atom(_) => self.fail("json: cannot represent an atom"),
+            MalEnv(_) => self.fail("json: cannot represent an environment"),
+            Code(_) => self.fail("json: cannot represent compiled code"),
+        }
+    }
+
+    fn fail(&mut self, msg: &str) -> fmt::Result {
+        self.error = Some(msg.to_string());
+        Err(fmt::Error)
+    }
+}
+
+// The boolean option name from the options map at a[i], if any
+fn json_opt(a: &MalArgs, i: usize, name: &str) -> Result<bool, MalErr> {
+    match a.get(i) {
+        None | Some(Nil) => Ok(false),
+        Some(Hash(hm, _)) => Ok(!matches!(
+            hm.get(&format!("\u{29e}{}", name)),
+            None | Some(Nil) | Some(Bool(false))
+        )),
+        _ => Err(ErrString("json: options must be a hash-map".to_string())),
+    }
+}
+
+fn json_read(s: &str, a: &MalArgs, i: usize) -> MalRet {
+    JsonReader {
+        s,
+        pos: 0,
+        keywordize: json_opt(a, i, "keywordize")?,
+    }
+    .read()
+}
+
+// (json-read-str s {:keywordize true})
+fn json_read_str(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(Str(s)) => json_read(s, &a, 1),
+        _ => error("json-read-str: expected a string"),
+    }
+}
+
+// (json-read-file f {:keywordize true})
+fn json_read_file(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(Str(f)) => json_read(&file_contents(f)?, &a, 1),
+        _ => error("json-read-file: expected a filename"),
+    }
+}
+
+fn json_write(v: &MalVal, pretty: bool) -> Result<String, MalErr> {
+    let mut s = String::new();
+    let mut jw = JsonWriter::new(&mut s, pretty);
+    match jw.write(v) {
+        Ok(()) => Ok(s),
+        Err(_) => Err(ErrString(jw.error.unwrap_or_default())),
+    }
+}
+
+// (json-write-str v {:pretty true})
+fn json_write_str(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(v) => Ok(Str(json_write(v, json_opt(&a, 1, "pretty")?)?)),
+        None => error("json-write-str: expected a value"),
+    }
+}
+
+// (json-write-file f v {:pretty true}) writes the JSON into the file f,
+// leaving the file alone when v cannot be represented
+fn json_write_file(a: MalArgs) -> MalRet {
+    let (f, v) = match (a.first(), a.get(1)) {
+        (Some(Str(f)), Some(v)) => (f, v),
+        _ => return error("json-write-file: expected a filename and a value"),
+    };
+    let json = json_write(v, json_opt(&a, 2, "pretty")?)?;
+    match File::create(f).and_then(|mut file| file.write_all(json.as_bytes())) {
+        Ok(_) => Ok(Nil),
+        Err(e) => error(&e.to_string()),
+    }
+}
+
+// (edn-read-string s) or (edn-read-string {:readers {...} :default f} s)
+fn edn_read_string(a: MalArgs) -> MalRet {
+    match (a.first(), a.get(1)) {
+        (Some(Str(s)), None) => read_edn(s.to_string(), Nil),
+        (Some(opts @ (Hash(..) | Nil)), Some(Str(s))) => read_edn(s.to_string(), opts.clone()),
+        _ => error("edn-read-string: expected an optional options map and a string"),
+    }
+}
+
+// Structural diff as in clojure.data/diff: maps are compared by key and
+// lists/vectors by index, anything else by equality.
+
+type DiffEntries<K> = [Vec<(K, MalVal)>; 3];
+
+fn diff_entry<K: Clone>(k: K, va: Option<&MalVal>, vb: Option<&MalVal>, res: &mut DiffEntries<K>) {
+    let (a, b, ab) = diff_vals(va.unwrap_or(&Nil), vb.unwrap_or(&Nil));
+    let same =
+        va.is_some() && vb.is_some() && (ab != Nil || (va == Some(&Nil) && vb == Some(&Nil)));
+    if va.is_some() && (a != Nil || !same) {
+        res[0].push((k.clone(), a));
+    }
+    if vb.is_some() && (b != Nil || !same) {
+        res[1].push((k.clone(), b));
+    }
+    if same {
+        res[2].push((k, ab));
+    }
+}
+
+fn diff_map(entries: Vec<(String, MalVal)>) -> MalVal {
+    if entries.is_empty() {
+        return Nil;
+    }
+    Hash(Rc::new(entries.into_iter().collect()), Rc::new(Nil))
+}
+
+// entries by index as a vector padded with nils
+fn diff_vector(entries: Vec<(usize, MalVal)>) -> MalVal {
+    let len = match entries.iter().map(|e| e.0).max() {
+        Some(i) => i + 1,
+        None => return Nil,
+    };
+    let mut v = vec![Nil; len];
+    for (i, x) in entries {
+        v[i] = x;
+    }
+    vector!(v)
+}
+
+// (things only in a, things only in b, things in both)
+fn diff_vals(a: &MalVal, b: &MalVal) -> (MalVal, MalVal, MalVal) {
+    if a == b {
+        return (Nil, Nil, a.clone());
+    }
+    match (a, b) {
+        (Hash(ha, _), Hash(hb, _)) => {
+            let mut res: DiffEntries<String> = Default::default();
+            let only_b = hb.keys().filter(|k| !ha.contains_key(*k));
+            for k in ha.keys().chain(only_b) {
+                diff_entry(k.to_string(), ha.get(k), hb.get(k), &mut res);
+            }
+            let [a, b, ab] = res;
+            (diff_map(a), diff_map(b), diff_map(ab))
+        }
+        (List(la, _) | Vector(la, _), List(lb, _) | Vector(lb, _)) => {
+            let mut res: DiffEntries<usize> = Default::default();
+            for i in 0..la.len().max(lb.len()) {
+                diff_entry(i, la.get(i), lb.get(i), &mut res);
+            }
+            let [a, b, ab] = res;
+            (diff_vector(a), diff_vector(b), diff_vector(ab))
+        }
+        _ => (a.clone(), b.clone(), Nil),
+    }
+}
+
+fn diff(a: MalArgs) -> MalRet {
+    if a.len() != 2 {
+        return error("diff: wrong number of args");
+    }
+    let (a, b, ab) = diff_vals(&a[0], &a[1]);
+    Ok(vector![a, b, ab])
+}
+
+// One "- path value" line for each value only in a and "+ path value"
+// for each only in b, where path is the vector of keys/indices to it
+fn diff_lines(
+    path: &mut Vec<MalVal>,
+    a: Option<&MalVal>,
+    b: Option<&MalVal>,
+    out: &mut Vec<String>,
+) {
+    match (a, b) {
+        (Some(a), Some(b)) if a == b => {}
+        (Some(Hash(ha, _)), Some(Hash(hb, _))) => {
+            let mut keys: Vec<&String> = ha.keys().chain(hb.keys()).collect();
+            keys.sort_by_key(|k| k.strip_prefix('\u{29e}').unwrap_or(k));
+            keys.dedup();
+            for k in keys {
+                path.push(Str(k.to_string()));
+                diff_lines(path, ha.get(k), hb.get(k), out);
+                path.pop();
+            }
+        }
+        (Some(List(la, _) | Vector(la, _)), Some(List(lb, _) | Vector(lb, _))) => {
+            for i in 0..la.len().max(lb.len()) {
+                path.push(Int(i as i64));
+                diff_lines(path, la.get(i), lb.get(i), out);
+                path.pop();
+            }
+        }
+        _ => {
+            let p = pr_seq(path, true, "[", "]", " ");
+            if let Some(a) = a {
+                out.push(format!("- {} {}", p, a.pr_str(true)));
+            }
+            if let Some(b) = b {
+                out.push(format!("+ {} {}", p, b.pr_str(true)));
+            }
+        }
+    }
+}
+
+fn diff_str(a: MalArgs) -> MalRet {
+    if a.len() != 2 {
+        return error("diff-str: wrong number of args");
+    }
+    let mut out = vec![];
+    diff_lines(&mut vec![], Some(&a[0]), Some(&a[1]), &mut out);
+    Ok(Str(out.join("\n")))
+}
+
 fn time_ms(_a: MalArgs) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
@@ -151,10 +796,10 @@ fn vec(a: MalArgs) -> MalRet {
 }
 
 fn cons(a: MalArgs) -> MalRet {
-    match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
+    match a[1] {
+        List(ref v, _) | Vector(ref v, _) => {
             let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
+            new_v.extend_from_slice(v);
             Ok(list!(new_v.to_vec()))
         }
         _ => error("cons expects seq as second arg"),
@@ -174,7 +819,7 @@ fn concat(a: MalArgs) -> MalRet {
 
 fn nth(a: MalArgs) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
+        (List(ref seq, _), Int(idx)) | (Vector(ref seq, _), Int(idx)) => {
             if seq.len() <= idx as usize {
                 return error("nth: index out of range");
             }
@@ -212,7 +857,7 @@ fn apply(a: MalArgs) -> MalRet {
         List(ref v, _) | Vector(ref v, _) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend_from_slice(v);
             f.apply(fargs)
         }
         _ => error("apply called with non-seq"),
@@ -260,92 +905,696 @@ fn seq(a: MalArgs) -> MalRet {
     }
 }
 
+// Attaches the name, argument lists and docstring of the builtin to f
+fn describe(name: &'static str, arglists: &str, doc: &str, f: MalVal) -> MalVal {
+    match f {
+        Func(f, ref meta, _) => Func(
+            f,
+            meta.clone(),
+            Rc::new(FnInfo {
+                name: Some(name.to_string()),
+                doc: Some(doc.to_string()),
+                arglists: read_str(arglists.to_string()).ok(),
+            }),
+        ),
+        f => f,
+    }
+}
+
+fn doc(a: MalArgs) -> MalRet {
+    if a.len() != 1 {
+        return error("doc: wrong number of args");
+    }
+    let info = match a[0].fn_info() {
+        Some(info) => info,
+        None => return error("doc: not a function"),
+    };
+    let mut s = String::from("-------------------------\n");
+    s.push_str(info.name.as_deref().unwrap_or("anonymous"));
+    s.push('\n');
+    if let List(ref l, _) = a[0].arglists()? {
+        s.push_str(&pr_seq(l, true, "(", ")\n", " "));
+    }
+    if let MalFunc { is_macro: true, .. } = a[0] {
+        s.push_str("Macro\n");
+    }
+    if let Some(ref d) = info.doc {
+        s.push_str("  ");
+        s.push_str(d);
+        s.push('\n');
+    }
+    print_out(|w| w.write_str(&s))
+}
+
+fn apropos(a: MalArgs) -> MalRet {
+    match a.first() {
+        Some(Str(s)) => {
+            let names = match current_ns().or_else(core_env) {
+                Some(ref env) => visible_names(env),
+                None => vec![],
+            };
+            Ok(list!(names
+                .into_iter()
+                .filter(|k| k.contains(&s[..]))
+                .map(|n| Sym(n.into()))
+                .collect()))
+        }
+        _ => error("apropos: expected a string"),
+    }
+}
+
+// environments
+
+fn env_arg<'a>(a: &'a MalArgs, f: &str) -> Result<&'a Env, MalErr> {
+    match a.first() {
+        Some(MalEnv(env)) => Ok(env),
+        _ => Err(ErrString(format!("{}: expected an environment", f))),
+    }
+}
+
+// (env-new) is an empty top-level environment, (env-new outer) a frame
+// within outer
+fn make_env(a: MalArgs) -> MalRet {
+    match a.first() {
+        None | Some(Nil) => Ok(MalEnv(env_new(None))),
+        Some(MalEnv(outer)) => Ok(MalEnv(env_new(Some(outer.clone())))),
+        _ => error("env-new: expected an environment or nil"),
+    }
+}
+
+fn env_names(a: MalArgs) -> MalRet {
+    let keys = env_keys(env_arg(&a, "env-keys")?);
+    Ok(list!(keys.into_iter().map(Sym).collect()))
+}
+
+// namespaces
+
+fn ns_name(v: Option<&MalVal>, f: &str) -> Result<Symbol, MalErr> {
+    match v {
+        Some(Sym(s)) => Ok(s.clone()),
+        _ => Err(ErrString(format!("{}: expected a namespace name", f))),
+    }
+}
+
+// (in-ns 'name) switches to the namespace, creating it
+fn in_ns(a: MalArgs) -> MalRet {
+    let name = ns_name(a.first(), "in-ns")?;
+    let env = match find_ns(&name) {
+        Some(env) => env,
+        None => env_new_ns(&name, core_env()),
+    };
+    set_current_ns(&env);
+    Ok(Sym(name))
+}
+
+// The first time it is required, namespace a.b-c is loaded from the file
+// a/b_c.mal (which is expected to start with (ns a.b-c))
+fn load_ns(name: &Symbol) -> Result<Env, MalErr> {
+    if let Some(env) = find_ns(name) {
+        return Ok(env);
+    }
+    let path = format!("{}.mal", name.replace('.', "/").replace('-', "_"));
+    let not_found = || ErrString(format!("namespace '{}' not found", name));
+    let load_file = match repl_env_get("load-file") {
+        Some(f) if Path::new(&path).exists() => f,
+        _ => return Err(not_found()),
+    };
+    let current = current_ns();
+    let res = load_file.apply(vec![Str(path)]);
+    if let Some(ref env) = current {
+        set_current_ns(env);
+    }
+    res?;
+    find_ns(name).ok_or_else(not_found)
+}
+
+// (require 'a '[b :as c :refer [x y]] '[d :refer :all])
+fn require(a: MalArgs) -> MalRet {
+    let current = match current_ns() {
+        Some(env) => env,
+        None => return error("require: no current namespace"),
+    };
+    for spec in a.iter() {
+        let (name, opts): (Symbol, &[MalVal]) = match spec {
+            Sym(s) => (s.clone(), &[][..]),
+            List(v, _) | Vector(v, _) if v.len() % 2 == 1 => {
+                (ns_name(v.first(), "require")?, &v[1..])
+            }
+            _ => return error("require: expected a namespace name or [name & options]"),
+        };
+        let env = load_ns(&name)?;
+        for opt in opts.chunks(2) {
+            let (k, v) = (&opt[0], &opt[1]);
+            match (k, v) {
+                (Str(k), Sym(alias)) if k == "\u{29e}as" => ns_alias(&current, alias, &name),
+                (Str(k), Str(all)) if k == "\u{29e}refer" && all == "\u{29e}all" => {
+                    for s in env_keys(&env) {
+                        ns_refer(&current, &s, &name);
+                    }
+                }
+                (Str(k), List(l, _)) | (Str(k), Vector(l, _)) if k == "\u{29e}refer" => {
+                    let defined = env_keys(&env);
+                    for s in l.iter() {
+                        match s {
+                            Sym(s) if defined.contains(s) => ns_refer(&current, s, &name),
+                            _ => {
+                                return error(&format!(
+                                    "require: {} is not defined in {}",
+                                    s.pr_str(true),
+                                    name
+                                ))
+                            }
+                        }
+                    }
+                }
+                _ => return error(&format!("require: invalid option {}", k.pr_str(true))),
+            }
+        }
+    }
+    Ok(Nil)
+}
+
+// The builtins, each with its argument lists and docstring
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
+    let ns = vec![
+        (
+            "=",
+            "([a b])",
+            "Returns true if a and b are equal.",
+            func(|a| Ok(Bool(a[0] == a[1]))),
+        ),
+        (
+            "throw",
+            "([v])",
+            "Throws v as an exception, to be caught by try*.",
+            func(|a| Err(ErrMalVal(a[0].clone()))),
+        ),
+        (
+            "nil?",
+            "([x])",
+            "Returns true if x is nil.",
+            func(fn_is_type!(Nil)),
+        ),
+        (
+            "true?",
+            "([x])",
+            "Returns true if x is true.",
+            func(fn_is_type!(Bool(true))),
+        ),
+        (
+            "false?",
+            "([x])",
+            "Returns true if x is false.",
+            func(fn_is_type!(Bool(false))),
+        ),
+        (
+            "symbol",
+            "([name])",
+            "Returns the symbol with the given name.",
+            func(symbol),
+        ),
+        (
+            "symbol?",
+            "([x])",
+            "Returns true if x is a symbol.",
+            func(fn_is_type!(Sym(_))),
+        ),
+        (
+            "gensym",
+            "([] [prefix])",
+            "Returns a new, unique symbol.",
+            func(gensym),
+        ),
         (
             "string?",
+            "([x])",
+            "Returns true if x is a string (and not a keyword).",
             func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
         ),
-        ("keyword", func(|a| a[0].keyword())),
+        (
+            "keyword",
+            "([name])",
+            "Returns the keyword with the given name.",
+            func(|a| a[0].keyword()),
+        ),
         (
             "keyword?",
+            "([x])",
+            "Returns true if x is a keyword.",
             func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
         ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        (
+            "number?",
+            "([x])",
+            "Returns true if x is a number.",
+            func(fn_is_type!(Int(_))),
+        ),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            "([x])",
+            "Returns true if x is a function (and not a macro).",
+            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(..))),
         ),
         (
             "macro?",
+            "([x])",
+            "Returns true if x is a macro.",
             func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
         ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
+        (
+            "pr-str",
+            "([& xs])",
+            "Prints the xs readably, separated by spaces, to a string.",
+            func(|a| Ok(Str(pr_seq(&a, true, "", "", " ")))),
+        ),
+        (
+            "str",
+            "([& xs])",
+            "Concatenates the xs, printed for people, into a string.",
+            func(|a| Ok(Str(str_seq(&a)))),
+        ),
         (
             "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
-            }),
+            "([& xs])",
+            "Prints the xs readably, separated by spaces, then a newline.",
+            func(|a| print_out(|w| pr_seq_to(w, &a, true, "", "\n", " "))),
         ),
         (
             "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
+            "([& xs])",
+            "Prints the xs for people, separated by spaces, then a newline.",
+            func(|a| print_out(|w| pr_seq_to(w, &a, false, "", "\n", " "))),
+        ),
+        (
+            "pprint-str",
+            "([x] [x width])",
+            "Pretty prints x in width (80) columns to a string.",
+            func(pprint_str),
+        ),
+        (
+            "pprint",
+            "([x] [x width])",
+            "Pretty prints x in width (80) columns.",
+            func(pprint),
+        ),
+        (
+            "read-string",
+            "([s])",
+            "Reads the first form in the string s.",
+            func(fn_str!(|s| { read_str(s) })),
+        ),
+        (
+            "edn-read-string",
+            "([s] [opts s])",
+            "Reads the first form in s as EDN data, with the tag :readers and :default fn in opts.",
+            func(edn_read_string),
+        ),
+        (
+            "edn-str",
+            "([& xs])",
+            "Prints the xs as EDN to a string, failing on values that are not data.",
+            func(|a| edn_str(&a)),
+        ),
+        (
+            "read-diagnostics",
+            "([s])",
+            "Returns the syntax errors in s as {:message :line :column} maps.",
+            func(fn_str!(|s| { read_diagnostics(s) })),
+        ),
+        (
+            "readline",
+            "([prompt])",
+            "Reads a line from the terminal, returning nil at end of input.",
+            func(readline),
+        ),
+        (
+            "slurp",
+            "([f])",
+            "Returns the contents of the file f as a string.",
+            func(fn_str!(|f| { slurp(f) })),
+        ),
+        (
+            "read-file",
+            "([f])",
+            "Reads the forms in the file f as a (do ...) form, skipping a #! first line.",
+            func(fn_str!(|f: String| { read_file(file_contents(&f)?) })),
+        ),
+        (
+            "spit",
+            "([f content & opts])",
+            "Writes content to the file f, appending with :append true.",
+            func(spit),
+        ),
+        (
+            "json-read-str",
+            "([s] [s opts])",
+            "Reads JSON from s, with keyword keys when opts has :keywordize true.",
+            func(json_read_str),
+        ),
+        (
+            "json-write-str",
+            "([x] [x opts])",
+            "Writes x as JSON to a string, indented when opts has :pretty true.",
+            func(json_write_str),
+        ),
+        (
+            "json-read-file",
+            "([f] [f opts])",
+            "Reads JSON from the file f, as json-read-str does.",
+            func(json_read_file),
+        ),
+        (
+            "json-write-file",
+            "([f x] [f x opts])",
+            "Writes x as JSON to the file f, as json-write-str does.",
+            func(json_write_file),
+        ),
+        (
+            "<",
+            "([a b])",
+            "Returns true if a is less than b.",
+            func(fn_t_int_int!(Bool, |i, j| { i < j })),
+        ),
+        (
+            "<=",
+            "([a b])",
+            "Returns true if a is less than or equal to b.",
+            func(fn_t_int_int!(Bool, |i, j| { i <= j })),
+        ),
+        (
+            ">",
+            "([a b])",
+            "Returns true if a is greater than b.",
+            func(fn_t_int_int!(Bool, |i, j| { i > j })),
+        ),
+        (
+            ">=",
+            "([a b])",
+            "Returns true if a is greater than or equal to b.",
+            func(fn_t_int_int!(Bool, |i, j| { i >= j })),
+        ),
+        (
+            "+",
+            "([a b])",
+            "Returns the sum of a and b.",
+            func(fn_t_int_int!(Int, |i, j| { i + j })),
+        ),
+        (
+            "-",
+            "([a b])",
+            "Returns a minus b.",
+            func(fn_t_int_int!(Int, |i, j| { i - j })),
+        ),
+        (
+            "*",
+            "([a b])",
+            "Returns the product of a and b.",
+            func(fn_t_int_int!(Int, |i, j| { i * j })),
+        ),
+        (
+            "/",
+            "([a b])",
+            "Returns a divided by b, rounded towards zero.",
+            func(fn_t_int_int!(Int, |i, j| { i / j })),
+        ),
+        (
+            "time-ms",
+            "([])",
+            "Returns the current time in milliseconds.",
+            func(time_ms),
+        ),
+        (
+            "sequential?",
+            "([x])",
+            "Returns true if x is a list or vector.",
+            func(fn_is_type!(List(_, _), Vector(_, _))),
+        ),
+        (
+            "list",
+            "([& items])",
+            "Returns a list of the items.",
+            func(|a| Ok(list!(a))),
+        ),
+        (
+            "list?",
+            "([x])",
+            "Returns true if x is a list.",
+            func(fn_is_type!(List(_, _))),
+        ),
+        (
+            "vector",
+            "([& items])",
+            "Returns a vector of the items.",
+            func(|a| Ok(vector!(a))),
+        ),
+        (
+            "vector?",
+            "([x])",
+            "Returns true if x is a vector.",
+            func(fn_is_type!(Vector(_, _))),
+        ),
+        (
+            "hash-map",
+            "([& kvs])",
+            "Returns a hash-map of the key/value pairs.",
+            func(|a| hash_map(a)),
+        ),
+        (
+            "map?",
+            "([x])",
+            "Returns true if x is a hash-map.",
+            func(fn_is_type!(Hash(_, _))),
+        ),
+        (
+            "assoc",
+            "([m & kvs])",
+            "Returns m with the key/value pairs added.",
+            func(assoc),
+        ),
+        (
+            "dissoc",
+            "([m & ks])",
+            "Returns m without the keys ks.",
+            func(dissoc),
+        ),
+        (
+            "get",
+            "([m k])",
+            "Returns the value of k in m, or nil.",
+            func(get),
+        ),
+        (
+            "contains?",
+            "([m k])",
+            "Returns true if m has the key k.",
+            func(contains_q),
+        ),
+        (
+            "keys",
+            "([m])",
+            "Returns a list of the keys of m.",
+            func(keys),
+        ),
+        (
+            "vals",
+            "([m])",
+            "Returns a list of the values of m.",
+            func(vals),
+        ),
+        (
+            "vec",
+            "([coll])",
+            "Returns a vector of the elements of coll.",
+            func(vec),
+        ),
+        (
+            "cons",
+            "([x coll])",
+            "Returns a list of x followed by the elements of coll.",
+            func(cons),
+        ),
+        (
+            "concat",
+            "([& colls])",
+            "Returns a list of the elements of the colls.",
+            func(concat),
+        ),
+        (
+            "empty?",
+            "([coll])",
+            "Returns true if coll has no elements.",
+            func(|a| a[0].empty_q()),
+        ),
+        (
+            "nth",
+            "([coll n])",
+            "Returns the nth element of coll.",
+            func(nth),
+        ),
+        (
+            "first",
+            "([coll])",
+            "Returns the first element of coll, or nil.",
+            func(first),
+        ),
+        (
+            "rest",
+            "([coll])",
+            "Returns a list of the elements of coll after the first.",
+            func(rest),
+        ),
+        (
+            "count",
+            "([coll])",
+            "Returns the number of elements in coll.",
+            func(|a| a[0].count()),
+        ),
+        (
+            "apply",
+            "([f & args])",
+            "Calls f with the args, the last of which is a list of more args.",
+            func(apply),
+        ),
+        (
+            "map",
+            "([f coll])",
+            "Returns a list of the results of calling f on each element of coll.",
+            func(map),
+        ),
+        (
+            "conj",
+            "([coll & xs])",
+            "Adds the xs to coll: at the front of lists and the end of vectors.",
+            func(conj),
+        ),
+        (
+            "seq",
+            "([x])",
+            "Returns a list of the elements or characters of x, or nil when empty.",
+            func(seq),
+        ),
+        (
+            "meta",
+            "([x])",
+            "Returns the metadata of x.",
+            func(|a| a[0].get_meta()),
+        ),
+        (
+            "with-meta",
+            "([x m])",
+            "Returns x with the metadata m.",
+            func(|a| a[0].clone().with_meta(&a[1])),
+        ),
+        (
+            "atom",
+            "([x])",
+            "Returns an atom holding x.",
+            func(|a| Ok(atom(&a[0]))),
+        ),
+        (
+            "atom?",
+            "([x])",
+            "Returns true if x is an atom.",
+            func(fn_is_type!(Atom(_))),
+        ),
+        (
+            "deref",
+            "([a])",
+            "Returns the value held by the atom a.",
+            func(|a| a[0].deref()),
+        ),
+        (
+            "reset!",
+            "([a x])",
+            "Sets the value of the atom a to x.",
+            func(|a| a[0].reset_bang(&a[1])),
+        ),
+        (
+            "swap!",
+            "([a f & args])",
+            "Sets the value of the atom a to (f value & args).",
+            func(|a| a[0].swap_bang(&a[1..].to_vec())),
+        ),
+        (
+            "doc",
+            "([f])",
+            "Prints the name, argument lists and docstring of f.",
+            func(doc),
+        ),
+        (
+            "arglists",
+            "([f])",
+            "Returns a list of the argument vectors of f.",
+            func(|a| a[0].arglists()),
+        ),
+        (
+            "apropos",
+            "([s])",
+            "Returns a sorted list of the defined symbols whose names contain s.",
+            func(apropos),
+        ),
+        (
+            "diff",
+            "([a b])",
+            "Returns [only-in-a only-in-b in-both], comparing maps by key and lists/vectors by index.",
+            func(diff),
+        ),
+        (
+            "diff-str",
+            "([a b])",
+            "Returns a line \"- path value\" for each value only in a and \"+ path value\" for each only in b.",
+            func(diff_str),
+        ),
+        (
+            "env?",
+            "([x])",
+            "Returns true if x is an environment.",
+            func(fn_is_type!(MalEnv(_))),
+        ),
+        (
+            "env-new",
+            "([] [outer])",
+            "Returns a new empty environment, or one whose bindings are looked up in outer when missing.",
+            func(make_env),
+        ),
+        (
+            "env-get",
+            "([env sym])",
+            "Returns the value of sym in env or its outer environments.",
+            func(|a| match a.len() {
+                2 => env_get(env_arg(&a, "env-get")?, &a[1]),
+                _ => error("env-get: wrong number of args"),
+            }),
+        ),
+        (
+            "env-set!",
+            "([env sym x])",
+            "Binds sym to x in env.",
+            func(|a| match a.len() {
+                3 => env_set(env_arg(&a, "env-set!")?, a[1].clone(), a[2].clone()),
+                _ => error("env-set!: wrong number of args"),
             }),
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
-    ]
+        (
+            "env-keys",
+            "([env])",
+            "Returns a list of the symbols bound in env itself.",
+            func(env_names),
+        ),
+        (
+            "in-ns",
+            "([name])",
+            "Makes the namespace name, created if need be, the current one.",
+            func(in_ns),
+        ),
+        (
+            "require",
+            "([& specs])",
+            "Loads each namespace (a.b-c from a/b_c.mal) unless it is loaded, where a spec is a name or [name :as alias :refer [syms]] (or :refer :all).",
+            func(require),
+        ),
+    ];
+    ns.into_iter()
+        .map(|(k, arglists, doc, f)| (k, describe(k, arglists, doc, f)))
+        .collect()
 }
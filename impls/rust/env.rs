@@ -1,39 +1,87 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{List, Nil, Sym, Vector};
-use crate::types::{error, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Hash, List, Nil, Str, Sym, Vector};
+use crate::types::{error, hash_map, MalArgs, MalErr, MalRet, MalVal, Symbol};
 
 #[derive(Debug)]
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    // Top-level environments (the REPL environment and namespaces) hold
+    // the globals in a hash; the frames of fn*, let* and catch* keep
+    // their few bindings in a vector, in the slots the analyzer resolved
+    // them to.
+    data: RefCell<FnvHashMap<Symbol, MalVal>>,
+    slots: RefCell<Vec<(Symbol, MalVal)>>,
+    pub ns: Option<Namespace>,
     pub outer: Option<Env>,
 }
 
 pub type Env = Rc<EnvStruct>;
 
+// The vars of a namespace are the bindings of its env. Besides them it
+// keeps the namespaces it knows by an alias, the names it refers from
+// other namespaces (each to the namespace it is in) and which of its
+// vars are dynamic.
+#[derive(Debug)]
+pub struct Namespace {
+    pub name: Symbol,
+    aliases: RefCell<FnvHashMap<Symbol, Symbol>>,
+    refers: RefCell<FnvHashMap<Symbol, Symbol>>,
+    dynamic: RefCell<FnvHashSet<Symbol>>,
+}
+
+thread_local! {
+    static CORE_ENV: RefCell<Option<Env>> = const { RefCell::new(None) };
+    static NAMESPACES: RefCell<FnvHashMap<Symbol, Env>> = RefCell::new(FnvHashMap::default());
+    static CURRENT_NS: RefCell<Option<Env>> = const { RefCell::new(None) };
+}
+
 // TODO: it would be nice to use impl here but it doesn't work on
 // a deftype (i.e. Env)
 
 pub fn env_new(outer: Option<Env>) -> Env {
     Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
+        slots: RefCell::new(vec![]),
+        ns: None,
         outer: outer,
     })
 }
 
+fn is_frame(env: &Env) -> bool {
+    env.outer.is_some() && env.ns.is_none()
+}
+
 // TODO: mbinds and exprs as & types
+#[allow(dead_code)] // step4 binds args only through MalVal::apply
 pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
     let env = env_new(outer);
     match mbinds {
-        List(binds, _) | Vector(binds, _) => {
+        List(ref binds, _) | Vector(ref binds, _) => {
+            let arity_error = || {
+                ErrString(format!(
+                    "wrong number of args ({}) passed to fn* with params {}",
+                    exprs.len(),
+                    mbinds.pr_str(true)
+                ))
+            };
+            let req = binds
+                .iter()
+                .take_while(|b| !matches!(b, Sym(s) if s == "&"))
+                .count();
+            if exprs.len() < req || (req == binds.len() && exprs.len() > req) {
+                return Err(arity_error());
+            }
             for (i, b) in binds.iter().enumerate() {
                 match b {
                     Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
+                        let rest = binds.get(i + 1).ok_or_else(|| {
+                            ErrString("fn* params: & is not followed by a name".to_string())
+                        })?;
+                        env_set(&env, rest.clone(), list!(exprs[i..].to_vec()))?;
                         break;
                     }
                     _ => {
@@ -47,39 +95,518 @@ pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Resul
     }
 }
 
-pub fn env_find(env: &Env, key: &str) -> Option<Env> {
-    match (env.data.borrow().contains_key(key), env.outer.clone()) {
-        (true, _) => Some(env.clone()),
-        (false, Some(o)) => env_find(&o, key),
-        _ => None,
+// destructuring
+
+pub type EvalFn = fn(MalVal, Env) -> MalRet;
+
+fn pattern_error(msg: &str, pattern: &MalVal) -> MalErr {
+    ErrString(format!(
+        "{} in binding pattern {}",
+        msg,
+        pattern.pr_str(true)
+    ))
+}
+
+fn is_kw(v: &MalVal, name: &str) -> bool {
+    match v {
+        Str(s) => s.strip_prefix('\u{29e}') == Some(name),
+        _ => false,
+    }
+}
+
+// Binds the args of a call to the params of a function, which form a
+// sequential pattern (an empty rest is the empty list)
+pub fn env_bind_args(
+    outer: Option<Env>,
+    params: &MalVal,
+    args: MalArgs,
+    eval: EvalFn,
+) -> Result<Env, MalErr> {
+    let env = env_new(outer);
+    match params {
+        List(p, _) | Vector(p, _) => bind_seq(&env, params, p, &args, eval)?,
+        _ => return Err(ErrString("fn* params must be a list or vector".to_string())),
+    }
+    Ok(env)
+}
+
+// Binds the names in a binding pattern to the parts of val. A pattern is
+// a symbol, a sequential pattern [a [b c] & rest :as all] or a map
+// pattern {:keys [a b] :strs [c] :or {:b 0} :as m}, nested as deep as
+// need be. The defaults of :or are evaluated in env when the key is
+// missing.
+pub fn env_destructure(
+    env: &Env,
+    pattern: &MalVal,
+    val: MalVal,
+    eval: EvalFn,
+) -> Result<(), MalErr> {
+    match pattern {
+        Sym(s) if s != "&" => {
+            env_put(env, s.clone(), val);
+        }
+        List(p, _) | Vector(p, _) => match val {
+            List(ref v, _) | Vector(ref v, _) => bind_seq(env, pattern, p, v, eval)?,
+            Nil => bind_seq(env, pattern, p, &[], eval)?,
+            _ => {
+                return Err(pattern_error(
+                    &format!("cannot destructure {} as a sequence", val.pr_str(true)),
+                    pattern,
+                ))
+            }
+        },
+        Hash(p, _) => {
+            let map = match val {
+                Hash(ref m, _) => m.clone(),
+                Nil => Rc::new(FnvHashMap::default()),
+                // e.g. the rest of the args as keyword arguments
+                List(ref v, _) | Vector(ref v, _) if v.len() % 2 == 0 => match hash_map(v.to_vec())
+                {
+                    Ok(Hash(ref m, _)) => m.clone(),
+                    _ => return Err(pattern_error("keys must be strings or keywords", pattern)),
+                },
+                _ => {
+                    return Err(pattern_error(
+                        &format!("cannot destructure {} as a map", val.pr_str(true)),
+                        pattern,
+                    ))
+                }
+            };
+            bind_map(env, pattern, p, &map, &val, eval)?;
+        }
+        _ => {
+            let msg = format!("invalid binding pattern {}", pattern.pr_str(true));
+            return Err(ErrString(msg));
+        }
+    }
+    Ok(())
+}
+
+fn bind_seq(
+    env: &Env,
+    pattern: &MalVal,
+    p: &[MalVal],
+    v: &[MalVal],
+    eval: EvalFn,
+) -> Result<(), MalErr> {
+    let mut i = 0;
+    while i < p.len() {
+        match p[i] {
+            Sym(ref s) if s == "&" => match p.get(i + 1) {
+                Some(rest) if !is_kw(rest, "as") => {
+                    let rest_val = list!(v.get(i..).unwrap_or(&[]).to_vec());
+                    env_destructure(env, rest, rest_val, eval)?;
+                    i += 2;
+                    break;
+                }
+                _ => return Err(pattern_error("& must be followed by a pattern", pattern)),
+            },
+            ref k if is_kw(k, "as") => break,
+            ref sub => {
+                env_destructure(env, sub, v.get(i).cloned().unwrap_or(Nil), eval)?;
+                i += 1;
+            }
+        }
+    }
+    match &p[i..] {
+        [] => Ok(()),
+        [k, Sym(name)] if is_kw(k, "as") => {
+            let all = match pattern {
+                Vector(..) => vector!(v.to_vec()),
+                _ => list!(v.to_vec()),
+            };
+            env_put(env, name.clone(), all);
+            Ok(())
+        }
+        [k, ..] if is_kw(k, "as") => Err(pattern_error(
+            ":as must be followed by a symbol and come last",
+            pattern,
+        )),
+        _ => Err(pattern_error(
+            "nothing may follow the rest pattern but :as",
+            pattern,
+        )),
+    }
+}
+
+fn bind_map(
+    env: &Env,
+    pattern: &MalVal,
+    p: &FnvHashMap<String, MalVal>,
+    map: &FnvHashMap<String, MalVal>,
+    val: &MalVal,
+    eval: EvalFn,
+) -> Result<(), MalErr> {
+    let defaults = match p.get("\u{29e}or") {
+        None => None,
+        Some(Hash(d, _)) => Some(d),
+        Some(_) => return Err(pattern_error(":or must be a map of defaults", pattern)),
+    };
+    for key in p.keys() {
+        if !["keys", "strs", "or", "as"]
+            .iter()
+            .any(|o| key.strip_prefix('\u{29e}') == Some(o))
+        {
+            let msg = format!("unsupported key {}", Str(key.to_string()).pr_str(true));
+            return Err(pattern_error(&msg, pattern));
+        }
+    }
+    for (opt, prefix) in [("keys", "\u{29e}"), ("strs", "")] {
+        let names = match p.get(&format!("\u{29e}{}", opt)) {
+            None => continue,
+            Some(List(n, _)) | Some(Vector(n, _)) => n,
+            Some(_) => {
+                return Err(pattern_error(
+                    &format!(":{} must be a vector of names", opt),
+                    pattern,
+                ))
+            }
+        };
+        for name in names.iter() {
+            let name = match name {
+                Sym(s) => s.clone(),
+                // :keys [:a] names a
+                Str(s) if s.starts_with('\u{29e}') => Symbol::new(&s[2..]),
+                _ => {
+                    let msg = format!(":{} must be a vector of names", opt);
+                    return Err(pattern_error(&msg, pattern));
+                }
+            };
+            let key = format!("{}{}", prefix, name);
+            let v = match map.get(&key) {
+                Some(v) => v.clone(),
+                None => match defaults.and_then(|d| d.get(&format!("\u{29e}{}", name))) {
+                    Some(d) => eval(d.clone(), env.clone())?,
+                    None => Nil,
+                },
+            };
+            env_put(env, name, v);
+        }
+    }
+    match p.get("\u{29e}as") {
+        None => {}
+        Some(Sym(name)) => env_put(env, name.clone(), val.clone()),
+        Some(_) => return Err(pattern_error(":as must be followed by a symbol", pattern)),
+    }
+    Ok(())
+}
+
+// The names a binding pattern binds, in the order they are bound
+#[allow(dead_code)] // only the stepA analyzer needs the names
+pub fn pattern_names(pattern: &MalVal, names: &mut Vec<Symbol>) {
+    match pattern {
+        Sym(s) if s != "&" => names.push(s.clone()),
+        List(p, _) | Vector(p, _) => {
+            let mut p = p.iter();
+            while let Some(sub) = p.next() {
+                match sub {
+                    k if is_kw(k, "as") => {
+                        if let Some(Sym(s)) = p.next() {
+                            names.push(s.clone());
+                        }
+                    }
+                    sub => pattern_names(sub, names),
+                }
+            }
+        }
+        Hash(p, _) => {
+            for opt in ["\u{29e}keys", "\u{29e}strs"] {
+                if let Some(List(n, _)) | Some(Vector(n, _)) = p.get(opt) {
+                    for name in n.iter() {
+                        match name {
+                            Sym(s) => names.push(s.clone()),
+                            Str(s) if s.starts_with('\u{29e}') => names.push(Symbol::new(&s[2..])),
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            if let Some(Sym(s)) = p.get("\u{29e}as") {
+                names.push(s.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+fn env_lookup(env: &Env, key: &Symbol) -> Option<MalVal> {
+    let mut env = env;
+    loop {
+        if is_frame(env) {
+            if let Some((_, v)) = env.slots.borrow().iter().find(|(k, _)| k == key) {
+                return Some(v.clone());
+            }
+        } else if let Some(v) = env.data.borrow().get(key) {
+            return Some(v.clone());
+        } else if let Some(v) = env.ns.as_ref().and_then(|ns| ns.referred(key)) {
+            return Some(v);
+        }
+        match env.outer {
+            Some(ref outer) => env = outer,
+            None => return None,
+        }
     }
 }
 
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
-        Sym(ref s) => match env_find(env, s) {
-            Some(e) => Ok(e
-                .data
-                .borrow()
-                .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
-                .clone()),
+        Sym(ref s) => match env_lookup(env, s).or_else(|| qualified_lookup(env, s)) {
+            Some(v) => Ok(v),
             _ => error(&format!("'{}' not found", s)),
         },
         _ => error("Env.get called with non-Str"),
     }
 }
 
+// A local the analyzer resolved: the slot of the frame depth levels up,
+// as long as it holds that name (a def! or a macro may have bound other
+// names first), otherwise the name is looked up as usual. The frames in
+// between are searched too, as a def! or env-set! at run time may have
+// bound the name in one of them, shadowing the slot.
+#[allow(dead_code)] // Local is stepA only
+pub fn env_get_local(env: &Env, name: &Symbol, depth: usize, slot: usize) -> MalRet {
+    let mut frame = env;
+    for _ in 0..depth {
+        if let Some((_, v)) = frame.slots.borrow().iter().find(|(k, _)| k == name) {
+            return Ok(v.clone());
+        }
+        match frame.outer {
+            Some(ref outer) => frame = outer,
+            None => break,
+        }
+    }
+    if let Some((k, v)) = frame.slots.borrow().get(slot) {
+        if k == name {
+            return Ok(v.clone());
+        }
+    }
+    match env_lookup(env, name) {
+        Some(v) => Ok(v),
+        _ => error(&format!("'{}' not found", name)),
+    }
+}
+
+fn env_put(env: &Env, key: Symbol, val: MalVal) {
+    if !is_frame(env) {
+        env.data.borrow_mut().insert(key, val);
+        return;
+    }
+    let mut slots = env.slots.borrow_mut();
+    match slots.iter_mut().find(|(k, _)| *k == key) {
+        Some(slot) => slot.1 = val,
+        None => slots.push((key, val)),
+    }
+}
+
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
         Sym(ref s) => {
-            env.data.borrow_mut().insert(s.to_string(), val.clone());
+            env_put(env, s.clone(), val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
     }
 }
 
+// The names bound in env itself: a frame's in slot order, a top-level
+// environment's sorted
+pub fn env_keys(env: &Env) -> Vec<Symbol> {
+    if is_frame(env) {
+        return env.slots.borrow().iter().map(|(k, _)| k.clone()).collect();
+    }
+    let mut keys: Vec<Symbol> = env.data.borrow().keys().cloned().collect();
+    keys.sort_by(|a, b| a[..].cmp(&b[..]));
+    keys
+}
+
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+    env_put(env, Symbol::new(key), val);
+}
+
+// The core namespace, where the builtins and the settings defined in
+// mal are, is registered so that native code (e.g. the reader resolving
+// *data-readers*) can consult them.
+#[allow(dead_code)] // only stepA has a mal.core namespace
+pub fn set_core_env(env: &Env) {
+    CORE_ENV.with(|e| *e.borrow_mut() = Some(env.clone()));
+}
+
+pub fn core_env() -> Option<Env> {
+    CORE_ENV.with(|e| e.borrow().clone())
+}
+
+// A setting as seen from the current namespace
+pub fn repl_env_get(key: &str) -> Option<MalVal> {
+    match current_ns().or_else(core_env) {
+        Some(ref env) => env_get(env, &Sym(Symbol::new(key))).ok(),
+        None => None,
+    }
+}
+
+// The names visible from the namespace env: its own, those it refers,
+// those of the namespaces it aliases (qualified by the alias) and those
+// of the core namespace
+pub fn visible_names(env: &Env) -> Vec<String> {
+    let mut names: Vec<String> = env.data.borrow().keys().map(|k| k.to_string()).collect();
+    if let Some(ref ns) = env.ns {
+        names.extend(ns.refers.borrow().keys().map(|k| k.to_string()));
+        for (alias, target) in ns.aliases.borrow().iter() {
+            if let Some(target) = find_ns(target) {
+                let data = target.data.borrow();
+                names.extend(data.keys().map(|k| format!("{}/{}", alias, k)));
+            }
+        }
+    }
+    match core_env() {
+        Some(ref core) if !Rc::ptr_eq(core, env) => {
+            names.extend(core.data.borrow().keys().map(|k| k.to_string()))
+        }
+        _ => {}
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+// namespaces
+
+// A new namespace, registered under name, whose env looks up missing
+// names in outer (the core namespace, where the builtins are)
+pub fn env_new_ns(name: &Symbol, outer: Option<Env>) -> Env {
+    let env = Rc::new(EnvStruct {
+        data: RefCell::new(FnvHashMap::default()),
+        slots: RefCell::new(vec![]),
+        ns: Some(Namespace {
+            name: name.clone(),
+            aliases: RefCell::new(FnvHashMap::default()),
+            refers: RefCell::new(FnvHashMap::default()),
+            dynamic: RefCell::new(FnvHashSet::default()),
+        }),
+        outer,
+    });
+    NAMESPACES.with(|n| n.borrow_mut().insert(name.clone(), env.clone()));
+    env
+}
+
+pub fn find_ns(name: &Symbol) -> Option<Env> {
+    NAMESPACES.with(|n| n.borrow().get(name).cloned())
+}
+
+pub fn current_ns() -> Option<Env> {
+    CURRENT_NS.with(|c| c.borrow().clone())
+}
+
+// Makes env the current namespace, which *ns* names
+pub fn set_current_ns(env: &Env) {
+    if let (Some(ns), Some(core_env)) = (&env.ns, core_env()) {
+        env_put(&core_env, Symbol::new("*ns*"), Sym(ns.name.clone()));
+    }
+    CURRENT_NS.with(|c| *c.borrow_mut() = Some(env.clone()));
+}
+
+pub fn ns_alias(env: &Env, alias: &Symbol, target: &Symbol) {
+    if let Some(ref ns) = env.ns {
+        ns.aliases
+            .borrow_mut()
+            .insert(alias.clone(), target.clone());
+    }
+}
+
+pub fn ns_refer(env: &Env, name: &Symbol, target: &Symbol) {
+    if let Some(ref ns) = env.ns {
+        ns.refers.borrow_mut().insert(name.clone(), target.clone());
+    }
+}
+
+impl Namespace {
+    // The value of a name referred from another namespace
+    fn referred(&self, key: &Symbol) -> Option<MalVal> {
+        let target = find_ns(self.refers.borrow().get(key)?)?;
+        let val = target.data.borrow().get(key).cloned();
+        val
+    }
+}
+
+// The namespace env a symbol qualified with a namespace, or an alias of
+// it known to the namespace env is in (str/join), refers to, and the
+// unqualified name
+fn qualified(env: &Env, key: &Symbol) -> Option<(Env, Symbol)> {
+    let i = key.find('/').filter(|&i| i > 0 && i < key.len() - 1)?;
+    let mut home = env;
+    while home.ns.is_none() {
+        home = home.outer.as_ref()?;
+    }
+    let prefix = Symbol::new(&key[..i]);
+    let target = match home.ns {
+        Some(ref ns) => ns.aliases.borrow().get(&prefix).cloned(),
+        None => None,
+    };
+    Some((
+        find_ns(&target.unwrap_or(prefix))?,
+        Symbol::new(&key[i + 1..]),
+    ))
+}
+
+fn qualified_lookup(env: &Env, key: &Symbol) -> Option<MalVal> {
+    let (target, name) = qualified(env, key)?;
+    let val = target.data.borrow().get(&name).cloned();
+    val
+}
+
+// dynamic vars
+
+// The top-level env holding the var a symbol names, whatever locals
+// are in scope, and its unqualified name
+pub fn env_var(env: &Env, key: &Symbol) -> Option<(Env, Symbol)> {
+    if let Some((target, name)) = qualified(env, key) {
+        let found = target.data.borrow().contains_key(&name);
+        return if found { Some((target, name)) } else { None };
+    }
+    let mut env = env;
+    loop {
+        if !is_frame(env) {
+            if env.data.borrow().contains_key(key) {
+                return Some((env.clone(), key.clone()));
+            }
+            if let Some(ref ns) = env.ns {
+                if let Some(target) = ns.refers.borrow().get(key).and_then(find_ns) {
+                    return Some((target, key.clone()));
+                }
+            }
+        }
+        env = env.outer.as_ref()?;
+    }
+}
+
+// Marks a var of the namespace env as dynamic, false if env isn't one
+#[allow(dead_code)] // ^:dynamic is read by the stepA def! only
+pub fn set_dynamic(env: &Env, key: &Symbol) -> bool {
+    match env.ns {
+        Some(ref ns) => {
+            ns.dynamic.borrow_mut().insert(key.clone());
+            true
+        }
+        None => false,
+    }
+}
+
+// Whether the var key of the namespace env is dynamic. A var shadowing a
+// dynamic one of the namespace outside, such as a namespace's own
+// *print-length*, is too.
+#[allow(dead_code)] // binding is a stepA special form
+pub fn is_dynamic(env: &Env, key: &Symbol) -> bool {
+    match env.ns {
+        Some(ref ns) if ns.dynamic.borrow().contains(key) => true,
+        Some(_) => match env.outer.as_ref().and_then(|outer| env_var(outer, key)) {
+            Some((target, name)) => is_dynamic(&target, &name),
+            None => false,
+        },
+        None => false,
+    }
+}
+
+// Sets a var to val, returning the value it had
+#[allow(dead_code)] // only the stepA binding restores vars
+pub fn env_swap(env: &Env, key: &Symbol, val: MalVal) -> Option<MalVal> {
+    env.data.borrow_mut().insert(key.clone(), val)
 }
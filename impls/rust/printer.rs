@@ -1,61 +1,783 @@
-use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-
-fn escape_str(s: &str) -> String {
-    s.chars()
-        .map(|c| match c {
-            '"' => "\\\"".to_string(),
-            '\n' => "\\n".to_string(),
-            '\\' => "\\\\".to_string(),
-            _ => c.to_string(),
-        })
-        .collect::<Vec<String>>()
-        .join("")
+use std::cell::RefCell;
+use std::collections::hash_map;
+use std::fmt::{self, Write};
+use std::io;
+use std::iter::Take;
+use std::rc::Rc;
+use std::slice;
+
+use crate::env::repl_env_get;
+use crate::types::MalVal::{
+    Atom, Bool, Code, Func, Hash, Int, List, Local, MalEnv, MalFunc, Nil, Str, Sym, Vector,
+};
+use crate::types::{arity, error, format_error, MalRet, MalVal};
+
+// Writes s with quotes, newlines and backslashes escaped, copying the
+// runs in between unchanged.
+fn escape_str(w: &mut dyn Write, s: &str) -> fmt::Result {
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        let esc = match c {
+            '"' => "\\\"",
+            '\n' => "\\n",
+            '\\' => "\\\\",
+            _ => continue,
+        };
+        w.write_str(&s[start..i])?;
+        w.write_str(esc)?;
+        start = i + 1;
+    }
+    w.write_str(&s[start..])
 }
 
-impl MalVal {
-    pub fn pr_str(&self, print_readably: bool) -> String {
-        match self {
-            Nil => String::from("nil"),
-            Bool(true) => String::from("true"),
-            Bool(false) => String::from("false"),
-            Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
-            Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
-                    format!("\"{}\"", escape_str(s))
-                } else {
-                    s.clone()
+fn write_string(w: &mut dyn Write, s: &str, print_readably: bool) -> fmt::Result {
+    if let Some(k) = s.strip_prefix('\u{29e}') {
+        w.write_char(':')?;
+        w.write_str(k)
+    } else if print_readably {
+        w.write_char('"')?;
+        escape_str(w, s)?;
+        w.write_char('"')
+    } else {
+        w.write_str(s)
+    }
+}
+
+// ANSI colours used when printing for a terminal
+pub const RESET: &str = "\x1b[0m";
+const STRING: &str = "\x1b[32m";
+const KEYWORD: &str = "\x1b[35m";
+const NUMBER: &str = "\x1b[36m";
+const LITERAL: &str = "\x1b[33m";
+#[allow(dead_code)] // only the stepA REPL shows errors in colour
+pub const ERROR: &str = "\x1b[31m";
+
+// Printing state: the atoms currently being printed, so that an atom
+// that (indirectly) contains itself prints as #<cycle>, and the depth
+// of nesting for *print-level*.
+struct Printer {
+    atoms: Vec<*const RefCell<MalVal>>,
+    length: Option<usize>,
+    level: Option<usize>,
+    depth: usize,
+    // printing EDN: values that are not data stop printing with an error
+    edn: bool,
+    error: Option<String>,
+    color: bool,
+    // set while printing what a :print function returned, so that
+    // returning the value itself does not recurse
+    no_method: bool,
+}
+
+thread_local! {
+    // The metadata of the values whose :print functions are running, so
+    // that printing the value again from inside prints it plainly
+    static PRINT_METHODS: RefCell<Vec<*const MalVal>> = const { RefCell::new(vec![]) };
+}
+
+// What is left to print of a value, borrowed from it
+enum Part<'a> {
+    // the items or entries of a collection left to print, the first
+    // printed without a space before it
+    Items(slice::Iter<'a, MalVal>, bool),
+    Entries(Take<hash_map::Iter<'a, String, MalVal>>, bool),
+    Text(&'static str),
+    // the end of a collection
+    Leave,
+}
+
+fn print_limit(name: &str) -> Option<usize> {
+    match repl_env_get(name) {
+        Some(Int(n)) if n >= 0 => Some(n as usize),
+        _ => None,
+    }
+}
+
+impl Printer {
+    fn new() -> Printer {
+        Printer {
+            atoms: vec![],
+            length: print_limit("*print-length*"),
+            level: print_limit("*print-level*"),
+            depth: 0,
+            edn: false,
+            error: None,
+            color: false,
+            no_method: false,
+        }
+    }
+
+    // Prints in full, ignoring *print-length* and *print-level*
+    fn unlimited() -> Printer {
+        Printer {
+            length: None,
+            level: None,
+            ..Printer::new()
+        }
+    }
+
+    fn edn() -> Printer {
+        Printer {
+            edn: true,
+            ..Printer::unlimited()
+        }
+    }
+
+    fn not_data(&mut self, what: &str) -> fmt::Result {
+        self.error = Some(format!("edn-str: cannot print {} as EDN", what));
+        Err(fmt::Error)
+    }
+
+    // One level deeper into a collection of len elements: how many of
+    // them to print (*print-length*), None when nested deeper than
+    // *print-level*. Whoever opens a collection leaves it once printed.
+    fn open(&mut self, len: usize) -> Option<usize> {
+        if self.level.is_some_and(|l| self.depth >= l) {
+            return None;
+        }
+        self.depth += 1;
+        Some(self.length.map_or(len, |n| len.min(n)))
+    }
+
+    // Calls f with how many elements to print within the opened
+    // collection
+    fn with_coll<T>(&mut self, len: usize, f: impl FnOnce(&mut Printer, usize) -> T) -> Option<T> {
+        let n = self.open(len)?;
+        let res = f(self, n);
+        self.depth -= 1;
+        Some(res)
+    }
+
+    // Calls f with the atom's value unless the atom is already being printed
+    fn with_atom<T>(
+        &mut self,
+        a: &Rc<RefCell<MalVal>>,
+        f: impl FnOnce(&mut Printer, &MalVal) -> T,
+    ) -> Option<T> {
+        let ptr = Rc::as_ptr(a);
+        if self.atoms.contains(&ptr) {
+            return None;
+        }
+        self.atoms.push(ptr);
+        let v = a.borrow().clone();
+        let res = f(self, &v);
+        self.atoms.pop();
+        Some(res)
+    }
+
+    // A value whose metadata has a :print function prints as what the
+    // function returns for it: a string as is, and anything else printed.
+    fn print_method(&mut self, v: &MalVal) -> Option<MalRet> {
+        if self.no_method {
+            self.no_method = false;
+            return None;
+        }
+        let meta = match v {
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Func(_, meta, _) => meta,
+            MalFunc { meta, .. } => meta,
+            _ => return None,
+        };
+        let f = match **meta {
+            Hash(ref hm, _) => hm.get("\u{29e}print")?,
+            _ => return None,
+        };
+        let ptr = Rc::as_ptr(meta);
+        if PRINT_METHODS.with(|m| m.borrow().contains(&ptr)) {
+            return None;
+        }
+        PRINT_METHODS.with(|m| m.borrow_mut().push(ptr));
+        let res = f.apply(vec![v.clone()]);
+        PRINT_METHODS.with(|m| m.borrow_mut().pop());
+        Some(res)
+    }
+
+    // Iterative, so that deeply nested collections print without
+    // overflowing the stack: parts holds what is left to print, the next
+    // one last. What is not part of v, the value of an atom or what a
+    // :print function returns, is printed by a call of its own.
+    fn pr(&mut self, w: &mut dyn Write, v: &MalVal, print_readably: bool) -> fmt::Result {
+        let depth = self.depth;
+        let mut parts = vec![];
+        let mut res = self.pr_part(w, v, print_readably, &mut parts);
+        while res.is_ok() {
+            match parts.pop() {
+                Some(part) => res = self.pr_rest(w, part, print_readably, &mut parts),
+                None => break,
+            }
+        }
+        self.depth = depth;
+        res
+    }
+
+    // Prints the next of the parts left
+    fn pr_rest<'a>(
+        &mut self,
+        w: &mut dyn Write,
+        part: Part<'a>,
+        print_readably: bool,
+        parts: &mut Vec<Part<'a>>,
+    ) -> fmt::Result {
+        match part {
+            Part::Items(mut items, first) => {
+                let x = match items.next() {
+                    Some(x) => x,
+                    None => return Ok(()),
+                };
+                parts.push(Part::Items(items, false));
+                if !first {
+                    w.write_char(' ')?;
+                }
+                self.pr_part(w, x, print_readably, parts)
+            }
+            Part::Entries(mut entries, first) => {
+                let (k, x) = match entries.next() {
+                    Some(entry) => entry,
+                    None => return Ok(()),
+                };
+                parts.push(Part::Entries(entries, false));
+                if !first {
+                    w.write_char(' ')?;
                 }
+                self.pr_string(w, k, print_readably)?;
+                w.write_char(' ')?;
+                self.pr_part(w, x, print_readably, parts)
+            }
+            Part::Text(s) => w.write_str(s),
+            Part::Leave => {
+                self.depth -= 1;
+                Ok(())
             }
-            Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+        }
+    }
+
+    // Prints v, or its start, pushing the parts that print the rest
+    fn pr_part<'a>(
+        &mut self,
+        w: &mut dyn Write,
+        v: &'a MalVal,
+        print_readably: bool,
+        parts: &mut Vec<Part<'a>>,
+    ) -> fmt::Result {
+        if let Some(res) = self.print_method(v) {
+            return match res {
+                Ok(Str(ref s)) => w.write_str(s),
+                Ok(r) => {
+                    self.no_method = true;
+                    self.pr(w, &r, print_readably)
+                }
+                Err(e) => write!(w, "#<print error: {}>", format_error(e)),
+            };
+        }
+        match v {
+            Nil => self.paint(w, LITERAL, |w| w.write_str("nil")),
+            Bool(true) => self.paint(w, LITERAL, |w| w.write_str("true")),
+            Bool(false) => self.paint(w, LITERAL, |w| w.write_str("false")),
+            Int(i) => self.paint(w, NUMBER, |w| write!(w, "{}", i)),
+            //Float(f)    => write!(w, "{}", f),
+            Str(s) => self.pr_string(w, s, print_readably),
+            Sym(s) | Local(s, ..) => w.write_str(s),
+            List(l, _) => self.pr_coll(w, l, "(", ")", parts),
+            Vector(l, _) => self.pr_coll(w, l, "[", "]", parts),
             Hash(hm, _) => {
-                let l: Vec<MalVal> = hm
-                    .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
-                    .collect();
-                pr_seq(&l, print_readably, "{", "}", " ")
+                let n = match self.open(hm.len()) {
+                    Some(n) => n,
+                    None => return w.write_char('#'),
+                };
+                w.write_char('{')?;
+                push_end(n, hm.len(), "}", parts);
+                parts.push(Part::Entries(hm.iter().take(n), true));
+                Ok(())
+            }
+            Func(..) | MalFunc { .. } if self.edn => self.not_data("a function"),
+            Atom(_) if self.edn => self.not_data("an atom"),
+            MalEnv(_) if self.edn => self.not_data("an environment"),
+            Func(_, _, info) | MalFunc { info, .. } => {
+                let kind = match v {
+                    MalFunc { is_macro: true, .. } => "macro",
+                    _ => "fn",
+                };
+                let name = info.name.as_deref().unwrap_or("anonymous");
+                write!(w, "#<{} {}", kind, name)?;
+                if let Ok(List(ref l, _)) = v.arglists() {
+                    // e.g. 2 for [a b] and 1+ for [a & more]
+                    let arities: Vec<String> = l
+                        .iter()
+                        .map(|p| match arity(p) {
+                            (n, true) => format!("{}+", n),
+                            (n, false) => n.to_string(),
+                        })
+                        .collect();
+                    write!(w, "/{}", arities.join(","))?;
+                }
+                w.write_char('>')
+            }
+            Atom(a) => {
+                let res = self.with_atom(a, |p, v| {
+                    w.write_str("(atom ")?;
+                    p.pr(w, v, true)?;
+                    w.write_char(')')
+                });
+                res.unwrap_or_else(|| w.write_str("#<cycle>"))
             }
-            Func(f, _) => format!("#<fn {:?}>", f),
-            MalFunc {
-                ast: a, params: p, ..
-            } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
-            Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
+            MalEnv(_) => w.write_str("#<env>"),
+            Code(_) => w.write_str("#<code>"),
+        }
+    }
+
+    fn pr_coll<'a>(
+        &mut self,
+        w: &mut dyn Write,
+        seq: &'a [MalVal],
+        start: &str,
+        end: &'static str,
+        parts: &mut Vec<Part<'a>>,
+    ) -> fmt::Result {
+        let n = match self.open(seq.len()) {
+            Some(n) => n,
+            None => return w.write_char('#'),
+        };
+        w.write_str(start)?;
+        push_end(n, seq.len(), end, parts);
+        parts.push(Part::Items(seq[..n].iter(), true));
+        Ok(())
+    }
+
+    fn pr_items(
+        &mut self,
+        w: &mut dyn Write,
+        seq: &[MalVal],
+        print_readably: bool,
+        join: &str,
+    ) -> fmt::Result {
+        for (i, x) in seq.iter().enumerate() {
+            if i > 0 {
+                w.write_str(join)?;
+            }
+            self.pr(w, x, print_readably)?;
+        }
+        Ok(())
+    }
+
+    // Writes what f writes, in the given colour when colouring
+    fn paint(
+        &self,
+        w: &mut dyn Write,
+        color: &str,
+        f: impl FnOnce(&mut dyn Write) -> fmt::Result,
+    ) -> fmt::Result {
+        if !self.color {
+            return f(w);
         }
+        w.write_str(color)?;
+        f(w)?;
+        w.write_str(RESET)
+    }
+
+    fn pr_string(&self, w: &mut dyn Write, s: &str, print_readably: bool) -> fmt::Result {
+        let color = if s.starts_with('\u{29e}') {
+            KEYWORD
+        } else {
+            STRING
+        };
+        self.paint(w, color, |w| write_string(w, s, print_readably))
+    }
+
+    fn pr_to_string(&mut self, v: &MalVal, print_readably: bool) -> String {
+        let mut s = String::new();
+        // writing to a String cannot fail
+        let _ = self.pr(&mut s, v, print_readably);
+        s
+    }
+}
+
+// The parts ending a collection of len items opened to print n of them:
+// "..." when there are more, and the end
+fn push_end(n: usize, len: usize, end: &'static str, parts: &mut Vec<Part>) {
+    parts.push(Part::Leave);
+    parts.push(Part::Text(end));
+    if n < len {
+        parts.push(Part::Text(if n == 0 { "..." } else { " ..." }));
+    }
+}
+
+impl MalVal {
+    pub fn pr_str(&self, print_readably: bool) -> String {
+        Printer::new().pr_to_string(self, print_readably)
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
+// Streams the elements of seq, separated by join, into w
+pub fn pr_seq_to(
+    w: &mut dyn Write,
+    seq: &[MalVal],
     print_readably: bool,
     start: &str,
     end: &str,
     join: &str,
-) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
-    format!("{}{}{}", start, strs.join(join), end)
+) -> fmt::Result {
+    w.write_str(start)?;
+    Printer::new().pr_items(w, seq, print_readably, join)?;
+    w.write_str(end)
+}
+
+pub fn pr_seq(seq: &[MalVal], print_readably: bool, start: &str, end: &str, join: &str) -> String {
+    let mut s = String::new();
+    let _ = pr_seq_to(&mut s, seq, print_readably, start, end, join);
+    s
+}
+
+// Streams the xs into w as str joins them: printed for people, and in
+// full, as *print-length* and *print-level* only limit what is shown
+pub fn str_to(w: &mut dyn Write, seq: &[MalVal]) -> fmt::Result {
+    Printer::unlimited().pr_items(w, seq, false, "")
+}
+
+pub fn str_seq(seq: &[MalVal]) -> String {
+    let mut s = String::new();
+    let _ = str_to(&mut s, seq);
+    s
+}
+
+// How the REPL shows a result: pretty printed in width columns if given,
+// optionally coloured for a terminal.
+#[allow(dead_code)] // only the stepA REPL pretty prints
+pub fn repl_str(v: &MalVal, width: Option<usize>, color: bool) -> String {
+    let mut p = Printer::new();
+    p.color = color;
+    match width {
+        Some(width) => {
+            let mut s = String::new();
+            let _ = layout(&mut s, &p.doc(v), width);
+            s
+        }
+        None => p.pr_to_string(v, true),
+    }
+}
+
+// Prints the elements of seq as EDN, or fails on values that are not data
+pub fn edn_str(seq: &[MalVal]) -> MalRet {
+    let mut s = String::new();
+    let mut p = Printer::edn();
+    match p.pr_items(&mut s, seq, true, " ") {
+        Ok(()) => Ok(Str(s)),
+        Err(_) => error(&p.error.unwrap_or_default()),
+    }
+}
+
+// Lets the printer write straight into an io::Write, keeping the io
+// error that made it stop.
+struct IoSink<'a, W: io::Write> {
+    inner: &'a mut W,
+    error: Option<io::Error>,
+}
+
+impl<'a, W: io::Write> Write for IoSink<'a, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
+    }
+}
+
+// Runs f with a fmt::Write sink over w, e.g.
+// write_io(&mut stdout, |w| pr_seq_to(w, ...))
+pub fn write_io<W: io::Write>(
+    w: &mut W,
+    f: impl FnOnce(&mut dyn Write) -> fmt::Result,
+) -> io::Result<()> {
+    let mut sink = IoSink {
+        inner: w,
+        error: None,
+    };
+    match f(&mut sink) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(sink
+            .error
+            .unwrap_or_else(|| io::Error::other("formatter error"))),
+    }
+}
+
+// Pretty printing: values are converted to a document which is then
+// laid out in the given width (Wadler, "A prettier printer").
+
+enum Doc {
+    Text(String),
+    // a newline, or a space when the enclosing group is flat
+    Line,
+    Nest(usize, Box<Doc>),
+    // indent to the column the document starts at
+    Align(Box<Doc>),
+    Group(Box<Doc>),
+    Concat(Vec<Doc>),
+}
+
+fn text(s: &str) -> Doc {
+    Doc::Text(s.to_string())
+}
+
+fn lines(docs: Vec<Doc>) -> Doc {
+    let mut res = vec![];
+    for (i, d) in docs.into_iter().enumerate() {
+        if i > 0 {
+            res.push(Doc::Line);
+        }
+        res.push(d);
+    }
+    Doc::Concat(res)
+}
+
+fn group(docs: Vec<Doc>) -> Doc {
+    Doc::Group(Box::new(Doc::Concat(docs)))
+}
+
+fn align(d: Doc) -> Doc {
+    Doc::Align(Box::new(d))
+}
+
+fn nest(i: usize, d: Doc) -> Doc {
+    Doc::Nest(i, Box::new(d))
+}
+
+// items separated by spaces, only breaking lines where needed
+fn fill(docs: Vec<Doc>) -> Doc {
+    let mut res = vec![];
+    for (i, d) in docs.into_iter().enumerate() {
+        if i > 0 {
+            res.push(group(vec![Doc::Line, d]));
+        } else {
+            res.push(d);
+        }
+    }
+    Doc::Concat(res)
+}
+
+const FLAT_DEPTH: usize = 200;
+
+// [a b c] with elements aligned under the first one
+fn seq_doc(open: &str, items: Vec<Doc>, close: &str) -> Doc {
+    group(vec![text(open), align(lines(items)), text(close)])
+}
+
+// key/value pairs (map entries, let* and loop bindings) one pair per line
+fn pairs_doc(open: &str, items: Vec<Doc>, close: &str) -> Doc {
+    let mut pairs = vec![];
+    let mut it = items.into_iter();
+    while let Some(k) = it.next() {
+        match it.next() {
+            Some(v) => pairs.push(Doc::Concat(vec![k, text(" "), v])),
+            None => pairs.push(k),
+        }
+    }
+    seq_doc(open, pairs, close)
+}
+
+// (head fixed... body...) with the fixed arguments kept on the first
+// line and the body indented by two
+fn body_doc(head: &str, fixed: Vec<Doc>, body: Vec<Doc>) -> Doc {
+    let mut first = vec![text(head)];
+    for d in fixed {
+        first.push(text(" "));
+        first.push(d);
+    }
+    let mut rest = vec![];
+    for d in body {
+        rest.push(Doc::Line);
+        rest.push(d);
+    }
+    first.push(nest(1, Doc::Concat(rest)));
+    group(vec![text("("), align(Doc::Concat(first)), text(")")])
+}
+
+impl Printer {
+    fn docs(&mut self, l: &[MalVal]) -> Vec<Doc> {
+        l.iter().map(|x| self.doc(x)).collect()
+    }
+
+    // sequences of scalars are filled rather than put one per line
+    fn coll_doc(&mut self, open: &str, l: &[MalVal], more: bool, close: &str) -> Doc {
+        let scalar = |x: &MalVal| !matches!(x, List(..) | Vector(..) | Hash(..) | Atom(_));
+        let mut docs = self.docs(l);
+        if more {
+            docs.push(text("..."));
+        }
+        if l.iter().all(scalar) {
+            group(vec![text(open), align(fill(docs)), text(close)])
+        } else {
+            seq_doc(open, docs, close)
+        }
+    }
+
+    fn list_doc(&mut self, l: &[MalVal]) -> Doc {
+        match l.first() {
+            Some(Sym(ref s)) if (s == "let*" || s == "loop") && l.len() >= 2 => {
+                let binds = match l[1] {
+                    List(ref b, _) | Vector(ref b, _) => {
+                        let (open, close) = match l[1] {
+                            List(..) => ("(", ")"),
+                            _ => ("[", "]"),
+                        };
+                        pairs_doc(open, self.docs(b), close)
+                    }
+                    ref b => self.doc(b),
+                };
+                body_doc(s, vec![binds], self.docs(&l[2..]))
+            }
+            Some(Sym(ref s))
+                if (s == "fn*"
+                    || s == "def!"
+                    || s == "defmacro!"
+                    || s == "if"
+                    || s == "catch*")
+                    && l.len() >= 2 =>
+            {
+                let fixed = vec![self.doc(&l[1])];
+                body_doc(s, fixed, self.docs(&l[2..]))
+            }
+            Some(Sym(ref s)) if s == "do" || s == "try*" => body_doc(s, vec![], self.docs(&l[1..])),
+            Some(Sym(ref s)) if l.len() > 1 => group(vec![
+                text("("),
+                text(s),
+                text(" "),
+                align(lines(self.docs(&l[1..]))),
+                text(")"),
+            ]),
+            _ => self.coll_doc("(", l, false, ")"),
+        }
+    }
+
+    fn doc(&mut self, v: &MalVal) -> Doc {
+        // nested deeper than any width allows, lay out flat rather than
+        // recursing further
+        if self.depth >= FLAT_DEPTH {
+            return Doc::Text(self.pr_to_string(v, true));
+        }
+        if let Some(res) = self.print_method(v) {
+            return match res {
+                Ok(Str(ref s)) => Doc::Text(s.clone()),
+                Ok(r) => {
+                    self.no_method = true;
+                    self.doc(&r)
+                }
+                Err(e) => Doc::Text(format!("#<print error: {}>", format_error(e))),
+            };
+        }
+        let res = match v {
+            List(l, _) => self.with_coll(l.len(), |p, n| {
+                if n < l.len() {
+                    p.coll_doc("(", &l[..n], true, ")")
+                } else {
+                    p.list_doc(l)
+                }
+            }),
+            Vector(l, _) => {
+                self.with_coll(l.len(), |p, n| p.coll_doc("[", &l[..n], n < l.len(), "]"))
+            }
+            Hash(hm, _) => self.with_coll(hm.len(), |p, n| {
+                let mut docs = vec![];
+                for (k, v) in hm.iter().take(n) {
+                    let mut key = String::new();
+                    let _ = p.pr_string(&mut key, k, true);
+                    docs.push(Doc::Text(key));
+                    docs.push(p.doc(v));
+                }
+                if n < hm.len() {
+                    docs.push(text("..."));
+                }
+                pairs_doc("{", docs, "}")
+            }),
+            Atom(a) => {
+                return match self.with_atom(a, |p, v| p.doc(v)) {
+                    Some(d) => group(vec![text("(atom "), align(d), text(")")]),
+                    None => text("#<cycle>"),
+                }
+            }
+            _ => return Doc::Text(self.pr_to_string(v, true)),
+        };
+        res.unwrap_or_else(|| text("#"))
+    }
+}
+
+impl MalVal {
+    // Lays the value out in width columns, streaming the result into w
+    pub fn pprint_to(&self, w: &mut dyn Write, width: usize) -> fmt::Result {
+        layout(w, &Printer::new().doc(self), width)
+    }
+
+    pub fn pprint_str(&self, width: usize) -> String {
+        let mut s = String::new();
+        let _ = self.pprint_to(&mut s, width);
+        s
+    }
+}
+
+// The number of columns s takes up, not counting colour sequences
+fn text_width(s: &str) -> usize {
+    let mut n = 0;
+    let mut esc = false;
+    for c in s.chars() {
+        if esc {
+            esc = c != 'm';
+        } else if c == '\x1b' {
+            esc = true;
+        } else {
+            n += 1;
+        }
+    }
+    n
+}
+
+// Does the flat layout of doc, followed by the rest of the document up to
+// its next line break, fit in width columns?
+fn fits(width: isize, doc: &Doc, rest: &[(usize, bool, &Doc)]) -> bool {
+    let mut rem = width;
+    let mut rest = rest.iter().rev();
+    let mut stack = vec![(0, true, doc)];
+    while rem >= 0 {
+        let (i, flat, d) = match stack.pop() {
+            Some(x) => x,
+            None => match rest.next() {
+                Some(&x) => x,
+                None => return true,
+            },
+        };
+        match d {
+            Doc::Text(s) => rem -= text_width(s) as isize,
+            Doc::Line if flat => rem -= 1,
+            Doc::Line => return true,
+            Doc::Nest(_, x) | Doc::Align(x) | Doc::Group(x) => stack.push((i, flat, x)),
+            Doc::Concat(v) => stack.extend(v.iter().rev().map(|x| (i, flat, x))),
+        }
+    }
+    false
+}
+
+fn layout(w: &mut dyn Write, doc: &Doc, width: usize) -> fmt::Result {
+    let mut col = 0;
+    // (indent, flat, doc), last element is laid out next
+    let mut stack = vec![(0, false, doc)];
+    while let Some((i, flat, d)) = stack.pop() {
+        match d {
+            Doc::Text(s) => {
+                w.write_str(s)?;
+                col += text_width(s);
+            }
+            Doc::Line if flat => {
+                w.write_char(' ')?;
+                col += 1;
+            }
+            Doc::Line => {
+                write!(w, "\n{:1$}", "", i)?;
+                col = i;
+            }
+            Doc::Nest(j, x) => stack.push((i + j, flat, x)),
+            Doc::Align(x) => stack.push((col, flat, x)),
+            Doc::Group(x) => {
+                let flat = flat || fits(width as isize - col as isize, x, &stack);
+                stack.push((i, flat, x));
+            }
+            Doc::Concat(v) => stack.extend(v.iter().rev().map(|x| (i, flat, x))),
+        }
+    }
+    Ok(())
 }
@@ -1,17 +1,85 @@
 use regex::{Captures, Regex};
 use std::rc::Rc;
 
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::env::repl_env_get;
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::{enter_depth, error, hash_map, MalErr, MalRet, MalVal};
+
+#[derive(Debug, Clone, Copy)]
+struct Pos {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Diagnostic {
+    message: String,
+    pos: Pos,
+}
 
 #[derive(Debug, Clone)]
 struct Reader {
     tokens: Vec<String>,
+    positions: Vec<Pos>,
+    eof: Pos,
     pos: usize,
+    in_fn_literal: bool,
+    recover: bool,
+    // when reading EDN, the options map (nil for none)
+    edn: Option<MalVal>,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Reader {
+    fn new(str: &str, recover: bool) -> Reader {
+        let (tokens, positions, eof) = tokenize(str);
+        Reader {
+            tokens,
+            positions,
+            eof,
+            pos: 0,
+            in_fn_literal: false,
+            recover,
+            edn: None,
+            diagnostics: vec![],
+        }
+    }
+    // position of the next token
+    fn position(&self) -> Pos {
+        *self.positions.get(self.pos).unwrap_or(&self.eof)
+    }
+    // Record a syntax error at pos. Values thrown by mal code (e.g. from
+    // a data reader) are recorded but passed through unchanged.
+    fn fail(&mut self, pos: Pos, e: MalErr) -> MalErr {
+        let message = match e {
+            ErrString(ref s) => s.clone(),
+            ErrMalVal(ref mv) => mv.pr_str(true),
+        };
+        self.diagnostics.push(Diagnostic {
+            message: message.clone(),
+            pos,
+        });
+        match e {
+            ErrString(_) => ErrString(format!(
+                "{} at line {}, column {}",
+                message, pos.line, pos.column
+            )),
+            e => e,
+        }
+    }
+    fn error(&mut self, pos: Pos, msg: &str) -> MalRet {
+        Err(self.fail(pos, ErrString(msg.to_string())))
+    }
+    // When recovering, an error (already recorded by fail) is dropped so
+    // that reading continues with the next form.
+    fn recover<T>(&self, res: Result<T, MalErr>) -> Result<Option<T>, MalErr> {
+        match res {
+            Ok(v) => Ok(Some(v)),
+            Err(_) if self.recover => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
     fn next(&mut self) -> Result<String, MalErr> {
         self.pos = self.pos + 1;
         Ok(self
@@ -29,22 +97,91 @@ impl Reader {
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
+// Skip a (possibly nested) #| ... |# block comment at the start of s
+fn skip_block_comment(s: &str) -> Option<&str> {
+    let b = s.as_bytes();
+    let (mut depth, mut i) = (0, 0);
+    while i < b.len() {
+        if b[i..].starts_with(b"#|") {
+            depth += 1;
+            i += 2;
+        } else if b[i..].starts_with(b"|#") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return Some(&s[i..]);
+            }
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+fn advance(pos: &mut Pos, consumed: &str) {
+    for c in consumed.chars() {
+        if c == '\n' {
+            pos.line += 1;
+            pos.column = 1;
+        } else {
+            pos.column += 1;
+        }
+    }
+}
+
+// Returns the tokens, their positions and the position of EOF. An
+// unterminated block comment is returned as a "#|" token. A #! line
+// is a comment only at the very start of str, for shebangs.
+fn tokenize(str: &str) -> (Vec<String>, Vec<Pos>, Pos) {
     lazy_static! {
         static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
+            r###"^(~@|#\(|#_|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
         )
         .unwrap();
     }
 
     let mut res = vec![];
-    for cap in RE.captures_iter(str) {
-        if cap[1].starts_with(";") {
+    let mut positions = vec![];
+    let mut at = Pos { line: 1, column: 1 };
+    let mut rest = str;
+    if rest.starts_with("#!") {
+        let end = rest.find('\n').unwrap_or(rest.len());
+        advance(&mut at, &rest[..end]);
+        rest = &rest[end..];
+    }
+    loop {
+        let trimmed = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        advance(&mut at, &rest[..rest.len() - trimmed.len()]);
+        rest = trimmed;
+        if rest.starts_with("#|") {
+            match skip_block_comment(rest) {
+                Some(r) => {
+                    advance(&mut at, &rest[..rest.len() - r.len()]);
+                    rest = r;
+                    continue;
+                }
+                None => {
+                    res.push(String::from("#|"));
+                    positions.push(at);
+                    advance(&mut at, rest);
+                    break;
+                }
+            }
+        }
+        let tok = match RE.find(rest) {
+            Some(m) => m.as_str(),
+            None => break,
+        };
+        rest = &rest[tok.len()..];
+        let start = at;
+        advance(&mut at, tok);
+        if tok.starts_with(';') {
             continue;
         }
-        res.push(String::from(&cap[1]));
+        res.push(String::from(tok));
+        positions.push(start);
     }
-    res
+    (res, positions, at)
 }
 
 fn unescape_str(s: &str) -> String {
@@ -62,6 +199,7 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
         static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
         static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
     }
+    let pos = rdr.position();
     let token = rdr.next()?;
     match &token[..] {
         "nil" => Ok(Nil),
@@ -69,88 +207,393 @@ fn read_atom(rdr: &mut Reader) -> MalRet {
         "true" => Ok(Bool(true)),
         _ => {
             if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
+                match token.parse() {
+                    Ok(i) => Ok(Int(i)),
+                    Err(_) => rdr.error(pos, "integer out of range"),
+                }
             } else if STR_RE.is_match(&token) {
                 Ok(Str(unescape_str(&token[1..token.len() - 1])))
             } else if token.starts_with("\"") {
-                error("expected '\"', got EOF")
+                rdr.error(pos, "expected '\"', got EOF")
             } else if token.starts_with(":") {
                 Ok(Str(format!("\u{29e}{}", &token[1..])))
             } else {
-                Ok(Sym(token.to_string()))
+                Ok(Sym(token.into()))
             }
         }
     }
 }
 
+// #_ discards the form that follows it (discards stack, so #_ #_ a b
+// discards both a and b)
+fn skip_discards(rdr: &mut Reader) -> Result<(), MalErr> {
+    while rdr.peek().is_ok_and(|t| t == "#_") {
+        rdr.next()?;
+        read_form(rdr)?;
+    }
+    Ok(())
+}
+
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let start = rdr.position();
+    let open = rdr.next()?;
     loop {
+        let res = skip_discards(rdr);
+        rdr.recover(res)?;
         let token = match rdr.peek() {
             Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+            Err(_) => {
+                let msg = format!("expected '{}', got EOF: unclosed '{}'", end, open);
+                return rdr.error(start, &msg);
+            }
         };
         if token == end {
             break;
         }
-        seq.push(read_form(rdr)?)
+        let res = read_form(rdr);
+        if let Some(form) = rdr.recover(res)? {
+            seq.push(form);
+        }
     }
     let _ = rdr.next();
     match end {
         ")" => Ok(list!(seq)),
         "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
+        "}" => hash_map(seq).map_err(|e| rdr.fail(start, e)),
         _ => error("read_seq unknown end value"),
     }
 }
 
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// #inst "1985-04-12T23:20:50.52Z" reads as milliseconds since the
+// epoch (the same representation as time-ms). As in Clojure, trailing
+// components may be omitted.
+fn read_inst(form: MalVal) -> MalRet {
+    lazy_static! {
+        static ref INST_RE: Regex = Regex::new(
+            r"^(\d{4})(?:-(\d{2})(?:-(\d{2})(?:T(\d{2}):(\d{2})(?::(\d{2})(?:\.(\d{1,9}))?)?)?)?)?(Z|[-+]\d{2}:\d{2})?$"
+        )
+        .unwrap();
+    }
+    let s = match form {
+        Str(ref s) if !form.keyword_q() => s.clone(),
+        _ => return error("#inst requires a string"),
+    };
+    let caps = match INST_RE.captures(&s) {
+        Some(c) => c,
+        None => return error(&format!("#inst: invalid timestamp \"{}\"", s)),
+    };
+    let num = |i: usize, default: i64| -> i64 {
+        caps.get(i).map_or(default, |m| m.as_str().parse().unwrap())
+    };
+    let (year, month, day) = (num(1, 0), num(2, 1), num(3, 1));
+    let (hour, min, sec) = (num(4, 0), num(5, 0), num(6, 0));
+    // the offset from UTC, +hh:mm or -hh:mm
+    let (off_sign, off_hour, off_min) = match caps.get(8).map(|m| m.as_str()) {
+        None | Some("Z") => (1, 0, 0),
+        Some(o) => (
+            if o.starts_with('-') { -1 } else { 1 },
+            o[1..3].parse::<i64>().unwrap(),
+            o[4..6].parse::<i64>().unwrap(),
+        ),
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=month_days).contains(&day)
+        || hour > 23
+        || min > 59
+        || sec > 60
+        || off_hour > 23
+        || off_min > 59
+    {
+        return error(&format!("#inst: invalid timestamp \"{}\"", s));
+    }
+    let ms = caps.get(7).map_or(0, |m| {
+        format!("{:0<3}", &m.as_str()[..m.as_str().len().min(3)])
+            .parse()
+            .unwrap()
+    });
+    let offset = off_sign * (off_hour * 60 + off_min);
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + min * 60 + sec - offset * 60;
+    Ok(Int(secs * 1000 + ms))
+}
+
+// #uuid "..." reads as the canonical (lower case) UUID string
+fn read_uuid(form: MalVal) -> MalRet {
+    lazy_static! {
+        static ref UUID_RE: Regex = Regex::new(
+            r"^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$"
+        )
+        .unwrap();
+    }
+    match form {
+        Str(ref s) if UUID_RE.is_match(s) => Ok(Str(s.to_lowercase())),
+        Str(ref s) if !form.keyword_q() => error(&format!("#uuid: invalid UUID \"{}\"", s)),
+        _ => error("#uuid requires a string"),
+    }
+}
+
+// Tags are resolved through the *data-readers* map (keyed by the tag
+// name as a string or keyword), then the built-in readers, and finally
+// *default-data-reader-fn* which is called with the tag symbol and form.
+// EDN is read with the :readers and :default of its options instead.
+// Collecting diagnostics only parses, so it runs no reader functions.
+fn tagged_literal(rdr: &Reader, tag: &str, form: MalVal) -> MalRet {
+    let (readers, default) = match rdr.edn {
+        Some(Hash(ref opts, _)) => (
+            opts.get("\u{29e}readers").cloned(),
+            opts.get("\u{29e}default").cloned(),
+        ),
+        Some(_) => (None, None),
+        None if rdr.recover => (None, None),
+        None => (
+            repl_env_get("*data-readers*"),
+            repl_env_get("*default-data-reader-fn*"),
+        ),
+    };
+    if let Some(Hash(ref hm, _)) = readers {
+        let f = hm.get(tag).or_else(|| hm.get(&format!("\u{29e}{}", tag)));
+        if let Some(f) = f {
+            return f.apply(vec![form]);
+        }
+    }
+    match tag {
+        "inst" => read_inst(form),
+        "uuid" => read_uuid(form),
+        _ if rdr.recover => Ok(form),
+        _ => match default {
+            Some(f @ Func(..)) | Some(f @ MalFunc { .. }) => f.apply(vec![Sym(tag.into()), form]),
+            _ => error(&format!("no reader function for tag {}", tag)),
+        },
+    }
+}
+
+fn read_tagged(rdr: &mut Reader) -> MalRet {
+    let pos = rdr.position();
+    let token = rdr.next()?;
+    let tag = &token[1..];
+    if !tag.starts_with(char::is_alphabetic) {
+        return rdr.error(pos, &format!("invalid dispatch macro '{}'", token));
+    }
+    if rdr.peek().is_err() {
+        return rdr.error(pos, &format!("expected form after '{}', got EOF", token));
+    }
+    let form = read_form(rdr)?;
+    tagged_literal(rdr, tag, form).map_err(|e| rdr.fail(pos, e))
+}
+
+// Replace the %-args of an anonymous function literal, recording the
+// highest positional arg used and whether %& appears.
+fn fn_literal_args(ast: &MalVal, max_arg: &mut usize, rest: &mut bool) -> MalVal {
+    match ast {
+        Sym(s) if s == "%" => {
+            *max_arg = (*max_arg).max(1);
+            Sym("%1".into())
+        }
+        Sym(s) if s == "%&" => {
+            *rest = true;
+            ast.clone()
+        }
+        Sym(s) if s.starts_with('%') => {
+            if let Ok(n) = s[1..].parse::<usize>() {
+                *max_arg = (*max_arg).max(n);
+            }
+            ast.clone()
+        }
+        List(l, meta) => List(
+            Rc::new(
+                l.iter()
+                    .map(|a| fn_literal_args(a, max_arg, rest))
+                    .collect(),
+            ),
+            meta.clone(),
+        ),
+        Vector(l, meta) => Vector(
+            Rc::new(
+                l.iter()
+                    .map(|a| fn_literal_args(a, max_arg, rest))
+                    .collect(),
+            ),
+            meta.clone(),
+        ),
+        Hash(hm, meta) => Hash(
+            Rc::new(
+                hm.iter()
+                    .map(|(k, v)| (k.to_string(), fn_literal_args(v, max_arg, rest)))
+                    .collect(),
+            ),
+            meta.clone(),
+        ),
+        _ => ast.clone(),
+    }
+}
+
+// #(f % %2 %&) reads as (fn* (%1 %2 & %&) (f %1 %2 %&))
+fn read_fn_literal(rdr: &mut Reader) -> MalRet {
+    let pos = rdr.position();
+    let nested = rdr.in_fn_literal;
+    rdr.in_fn_literal = true;
+    let body = read_seq(rdr, ")");
+    rdr.in_fn_literal = nested;
+    if nested {
+        return rdr.error(pos, "nested #()s are not allowed");
+    }
+    let body = body?;
+    let (mut max_arg, mut rest) = (0, false);
+    let body = fn_literal_args(&body, &mut max_arg, &mut rest);
+    let mut params: Vec<MalVal> = (1..=max_arg)
+        .map(|i| Sym(format!("%{}", i).into()))
+        .collect();
+    if rest {
+        params.push(Sym("&".into()));
+        params.push(Sym("%&".into()));
+    }
+    Ok(list![Sym("fn*".into()), list!(params), body])
+}
+
 fn read_form(rdr: &mut Reader) -> MalRet {
-    let token = rdr.peek()?;
+    let pos = rdr.position();
+    let _depth = match enter_depth() {
+        Ok(d) => d,
+        Err(e) => return Err(rdr.fail(pos, e)),
+    };
+    let token = match rdr.peek() {
+        Ok(t) => t,
+        Err(_) => return rdr.error(pos, "expected form, got EOF"),
+    };
     match &token[..] {
+        "'" | "`" | "~" | "~@" | "^" | "@" | "#(" if rdr.edn.is_some() => {
+            let _ = rdr.next();
+            rdr.error(pos, &format!("'{}' is not allowed in EDN", token))
+        }
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("quote".into()), read_form(rdr)?])
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("quasiquote".into()), read_form(rdr)?])
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("unquote".into()), read_form(rdr)?])
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            Ok(list![Sym("splice-unquote".into()), read_form(rdr)?])
         }
         "^" => {
             let _ = rdr.next();
             let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            Ok(list![Sym("with-meta".into()), read_form(rdr)?, meta])
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+            Ok(list![Sym("deref".into()), read_form(rdr)?])
+        }
+        ")" | "]" | "}" => {
+            let _ = rdr.next();
+            rdr.error(pos, &format!("unexpected '{}'", token))
         }
-        ")" => error("unexpected ')'"),
         "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
+        "#(" => read_fn_literal(rdr),
+        "#_" => {
+            skip_discards(rdr)?;
+            read_form(rdr)
+        }
         "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
         "{" => read_seq(rdr, "}"),
+        "#|" => {
+            let _ = rdr.next();
+            rdr.error(pos, "expected '|#', got EOF: unclosed '#|'")
+        }
+        "#" => {
+            let _ = rdr.next();
+            match rdr.peek() {
+                Ok(ref t) if t == "{" => rdr.error(pos, "sets are not supported"),
+                _ => rdr.error(pos, "'#' must be followed by a tag"),
+            }
+        }
+        t if t.starts_with('#') => read_tagged(rdr),
         _ => read_atom(rdr),
     }
 }
 
 pub fn read_str(str: String) -> MalRet {
-    let tokens = tokenize(&str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.len() == 0 {
+    let mut rdr = Reader::new(&str, false);
+    skip_discards(&mut rdr)?;
+    if rdr.peek().is_err() {
         return error("no input");
     }
-    read_form(&mut Reader {
-        pos: 0,
-        tokens: tokens,
-    })
+    read_form(&mut rdr)
+}
+
+// Read all the forms in the source of a file as (do forms... nil)
+pub fn read_file(str: String) -> MalRet {
+    let mut rdr = Reader::new(&str, false);
+    let mut forms = vec![Sym("do".into())];
+    loop {
+        skip_discards(&mut rdr)?;
+        if rdr.peek().is_err() {
+            break;
+        }
+        forms.push(read_form(&mut rdr)?);
+    }
+    forms.push(Nil);
+    Ok(list!(forms))
+}
+
+// Read the first form of str as EDN: data only, so none of the reader
+// macros that expand into code are allowed. Empty input reads as nil.
+pub fn read_edn(str: String, opts: MalVal) -> MalRet {
+    let mut rdr = Reader::new(&str, false);
+    rdr.edn = Some(opts);
+    skip_discards(&mut rdr)?;
+    if rdr.peek().is_err() {
+        return Ok(Nil);
+    }
+    read_form(&mut rdr)
+}
+
+// Read every form in str, recovering from syntax errors, and return the
+// errors found as a vector of {:message :line :column} maps, in source
+// order.
+pub fn read_diagnostics(str: String) -> MalRet {
+    let mut rdr = Reader::new(&str, true);
+    loop {
+        let _ = skip_discards(&mut rdr);
+        if rdr.peek().is_err() {
+            break;
+        }
+        let _ = read_form(&mut rdr);
+    }
+    rdr.diagnostics.sort_by_key(|d| (d.pos.line, d.pos.column));
+    let mut diags = vec![];
+    for d in rdr.diagnostics.iter() {
+        diags.push(hash_map(vec![
+            Str("\u{29e}message".to_string()),
+            Str(d.message.clone()),
+            Str("\u{29e}line".to_string()),
+            Int(d.pos.line as i64),
+            Str("\u{29e}column".to_string()),
+            Int(d.pos.column as i64),
+        ])?);
+    }
+    Ok(vector!(diags))
 }
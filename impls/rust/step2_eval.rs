@@ -18,7 +18,10 @@ mod types;
 use crate::types::MalErr::ErrString;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
 use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+// printing and reading files, EDN and diagnostics are for core
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
 // TODO: figure out a way to avoid including env
 #[allow(dead_code)]
@@ -35,7 +38,7 @@ fn read(str: &str) -> MalRet {
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
         Sym(sym) => Ok(env
-            .get(sym)
+            .get(&sym[..])
             .ok_or(ErrString(format!("'{}' not found", sym)))?
             .clone()),
         List(v, _) => {
@@ -65,7 +68,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
-        List(l, _) => {
+        List(ref l, _) => {
             if l.len() == 0 {
                 return Ok(ast);
             }
@@ -18,8 +18,13 @@ use rustyline::Editor;
 mod types;
 use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
 use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
+// namespaces are for core and stepA
+#[allow(dead_code)]
 mod env;
+// printing and reading files, EDN and diagnostics are for core
+#[allow(dead_code)]
 mod printer;
+#[allow(dead_code)]
 mod reader;
 use crate::env::{env_get, env_new, env_set, env_sets, Env};
 
@@ -59,7 +64,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
-        List(l, _) => {
+        List(ref l, _) => {
             if l.len() == 0 {
                 return Ok(ast);
             }
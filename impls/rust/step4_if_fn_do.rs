@@ -60,7 +60,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
-        List(l, _) => {
+        List(ref l, _) => {
             if l.len() == 0 {
                 return Ok(ast);
             }
@@ -96,7 +96,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                     eval(a2, let_env)
                 }
                 Sym(ref a0sym) if a0sym == "do" => match eval_ast(&list!(l[1..].to_vec()), &env)? {
-                    List(el, _) => Ok(el.last().unwrap_or(&Nil).clone()),
+                    List(ref el, _) => Ok(el.last().unwrap_or(&Nil).clone()),
                     _ => error("invalid do form"),
                 },
                 Sym(ref a0sym) if a0sym == "if" => {
@@ -117,6 +117,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                         params: Rc::new(a1),
                         is_macro: false,
                         meta: Rc::new(Nil),
+                        info: Default::default(),
                     })
                 }
                 _ => match eval_ast(&ast, &env)? {
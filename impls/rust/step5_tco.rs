@@ -63,7 +63,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
 
     'tco: loop {
         ret = match ast.clone() {
-            List(l, _) => {
+            List(ref l, _) => {
                 if l.len() == 0 {
                     return Ok(ast);
                 }
@@ -132,6 +132,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            info: Default::default(),
                         })
                     }
                     _ => match eval_ast(&ast, &env)? {
@@ -139,7 +140,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             let ref f = el[0].clone();
                             let args = el[1..].to_vec();
                             match f {
-                                Func(_, _) => f.apply(args),
+                                Func(..) => f.apply(args),
                                 MalFunc {
                                     ast: mast,
                                     env: menv,
@@ -38,13 +38,13 @@ fn qq_iter(elts: &MalArgs) -> MalVal {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![Sym("concat".into()), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![Sym("cons".into()), quasiquote(&elt), acc];
     }
     return acc;
 }
@@ -61,8 +61,8 @@ fn quasiquote(ast: &MalVal) -> MalVal {
             }
             return qq_iter(&v);
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => return list![Sym("vec".into()), qq_iter(&v)],
+        Hash(_, _) | Sym(_)=> return list![Sym("quote".into()), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -100,7 +100,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
 
     'tco: loop {
         ret = match ast.clone() {
-            List(l, _) => {
+            List(ref l, _) => {
                 if l.len() == 0 {
                     return Ok(ast);
                 }
@@ -175,6 +175,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            info: Default::default(),
                         })
                     }
                     Sym(ref a0sym) if a0sym == "eval" => {
@@ -189,7 +190,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             let ref f = el[0].clone();
                             let args = el[1..].to_vec();
                             match f {
-                                Func(_, _) => f.apply(args),
+                                Func(..) => f.apply(args),
                                 MalFunc {
                                     ast: mast,
                                     env: menv,
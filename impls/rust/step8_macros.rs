@@ -20,7 +20,7 @@ use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
 mod env;
 mod printer;
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{env_bind, env_get, env_new, env_set, env_sets, Env};
 #[macro_use]
 mod core;
 
@@ -38,13 +38,13 @@ fn qq_iter(elts: &MalArgs) -> MalVal {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![Sym("concat".into()), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![Sym("cons".into()), quasiquote(&elt), acc];
     }
     return acc;
 }
@@ -61,8 +61,8 @@ fn quasiquote(ast: &MalVal) -> MalVal {
             }
             return qq_iter(&v);
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => return list![Sym("vec".into()), qq_iter(&v)],
+        Hash(_, _) | Sym(_)=> return list![Sym("quote".into()), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -70,11 +70,8 @@ fn quasiquote(ast: &MalVal) -> MalVal {
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
         List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
-                Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
-                    _ => None,
-                },
+            Sym(_) => match env_get(env, &v[0]) {
+                Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
                 _ => None,
             },
             _ => None,
@@ -130,7 +127,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
 
     'tco: loop {
         ret = match ast.clone() {
-            List(l, _) => {
+            List(ref l, _) => {
                 if l.len() == 0 {
                     return Ok(ast);
                 }
@@ -190,12 +187,12 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         match r {
                             MalFunc {
                                 eval,
-                                ast,
-                                env,
-                                params,
+                                ref ast,
+                                ref env,
+                                ref params,
                                 ..
                             } => Ok(env_set(
-                                &env,
+                                env,
                                 a1.clone(),
                                 MalFunc {
                                     eval: eval,
@@ -204,6 +201,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                     params: params.clone(),
                                     is_macro: true,
                                     meta: Rc::new(Nil),
+                                    info: Default::default(),
                                 },
                             )?),
                             _ => error("set_macro on non-function"),
@@ -248,6 +246,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             params: Rc::new(a1),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            info: Default::default(),
                         })
                     }
                     Sym(ref a0sym) if a0sym == "eval" => {
@@ -262,7 +261,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                             let ref f = el[0].clone();
                             let args = el[1..].to_vec();
                             match f {
-                                Func(_, _) => f.apply(args),
+                                Func(..) => f.apply(args),
                                 MalFunc {
                                     ast: mast,
                                     env: menv,
@@ -1,5 +1,6 @@
 #![allow(non_snake_case)]
 
+use std::io::IsTerminal;
 use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
@@ -18,12 +19,24 @@ use rustyline::Editor;
 #[macro_use]
 mod types;
 use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{
+    Bool, Code, Func, Hash, Int, List, Local, MalEnv, MalFunc, Nil, Str, Sym, Vector,
+};
+use crate::types::{
+    enter_depth, error, format_error, set_stack_limits, FnInfo,
+    MalArgs, MalErr, MalRet, MalVal, Symbol, DEFAULT_MAX_DEPTH, STACK_PER_LEVEL,
+};
+mod analyzer;
 mod env;
 mod printer;
+mod vm;
+use crate::printer::{repl_str, ERROR, RESET};
 mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
+use crate::env::{
+    current_ns, env_bind, env_bind_args, env_destructure, env_get, env_get_local, env_new,
+    env_new_ns, env_set, env_sets, env_swap, env_var, is_dynamic, repl_env_get, set_core_env,
+    set_current_ns, set_dynamic, Env,
+};
 #[macro_use]
 mod core;
 
@@ -34,25 +47,27 @@ fn read(str: &str) -> MalRet {
 
 // eval
 
-fn qq_iter(elts: &MalArgs) -> MalVal {
+fn qq_iter(elts: &MalArgs, gensyms: &mut FnvHashMap<Symbol, MalVal>) -> MalVal {
     let mut acc = list![];
     for elt in elts.iter().rev() {
         if let List(v, _) = elt {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![Sym("concat".into()), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![Sym("cons".into()), quasiquote(&elt, gensyms), acc];
     }
     return acc;
 }
 
-fn quasiquote(ast: &MalVal) -> MalVal {
+// Within a single quasiquote every foo# expands to the same generated
+// symbol so that macros can introduce bindings without capture.
+fn quasiquote(ast: &MalVal, gensyms: &mut FnvHashMap<Symbol, MalVal>) -> MalVal {
     match ast {
         List(v, _) => {
             if v.len() == 2 {
@@ -62,10 +77,18 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
+            return qq_iter(&v, gensyms);
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => return list![Sym("vec".into()), qq_iter(&v, gensyms)],
+        Sym(s) if s.len() > 1 && s.ends_with('#') => {
+            let prefix = format!("{}__", &s[..s.len() - 1]);
+            let sym = gensyms
+                .entry(s.clone())
+                .or_insert_with(|| Sym(format!("{}__auto__", core::gensym_str(&prefix)).into()))
+                .clone();
+            list![Sym("quote".into()), sym]
+        }
+        Hash(_, _) | Sym(_)=> return list![Sym("quote".into()), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -73,11 +96,16 @@ fn quasiquote(ast: &MalVal) -> MalVal {
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
         List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
-                Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
-                    _ => None,
-                },
+            Sym(_) => match env_get(env, &v[0]) {
+                Ok(f @ MalFunc { is_macro: true, .. }) => {
+                    // a macro is given the forms as written
+                    let args = &v[1..];
+                    if args.iter().any(analyzer::is_resolved) {
+                        Some((f, args.iter().map(analyzer::unresolve).collect()))
+                    } else {
+                        Some((f, args.to_vec()))
+                    }
+                }
                 _ => None,
             },
             _ => None,
@@ -100,9 +128,76 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
     ((was_expanded, Ok(ast)))
 }
 
+// The body of a fn* clause: its forms evaluated in turn
+fn fn_body(forms: &[MalVal]) -> MalVal {
+    match forms {
+        [] => Nil,
+        [form] => form.clone(),
+        _ => {
+            let mut body = vec![Sym(Symbol::new("do"))];
+            body.extend_from_slice(forms);
+            list!(body)
+        }
+    }
+}
+
+// The docstring, params and body of a fn* form: (fn* params "doc" body)
+// has a docstring, and (fn* ([x] body) ([x y] body)) a clause per
+// arity, kept as (params body) lists with nil params
+fn fn_parts(l: &[MalVal]) -> (Option<String>, MalVal, MalVal) {
+    if analyzer::is_multi_arity(&l[1..]) {
+        let clauses = l[1..].iter().map(|c| match c {
+            List(c, _) => list![c[0].clone(), fn_body(&c[1..])],
+            _ => unreachable!(),
+        });
+        return (None, Nil, list!(clauses.collect()));
+    }
+    match (l.len(), l.get(2)) {
+        (4, Some(Str(ref d))) if !d.starts_with('\u{29e}') => {
+            (Some(d.to_string()), l[1].clone(), l[3].clone())
+        }
+        _ => (None, l[1].clone(), l.get(2).cloned().unwrap_or(Nil)),
+    }
+}
+
+// The macro defmacro! makes of the function f
+fn make_macro(f: MalVal, name: &MalVal) -> MalRet {
+    match f {
+        MalFunc {
+            eval,
+            ref ast,
+            ref env,
+            ref params,
+            ref info,
+            ..
+        } => {
+            vm::macros_changed();
+            let f = MalFunc {
+                eval: eval,
+                ast: ast.clone(),
+                env: env.clone(),
+                params: params.clone(),
+                is_macro: true,
+                meta: Rc::new(Nil),
+                info: info.clone(),
+            };
+            Ok(match name {
+                Sym(ref name) => f.named(name),
+                _ => f,
+            })
+        }
+        _ => error("set_macro on non-function"),
+    }
+}
+
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
+        Sym(s) => match env_get(&env, &ast) {
+            // unless rebound, *ENV* is the environment it is evaluated in
+            Err(_) if s == "*ENV*" => Ok(MalEnv(env.clone())),
+            res => res,
+        },
+        Local(name, depth, slot) => env_get_local(env, name, *depth, *slot),
         List(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
@@ -129,11 +224,17 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 }
 
 fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
+    let _depth = enter_depth()?;
     let ret: MalRet;
+    // the loop a recur reached in tail position starts over: its
+    // bindings, frame and body (only a tail position continues the loop
+    // below, and a call or eval there leaves the loop behind)
+    let mut recur_to: Option<(Rc<Vec<MalVal>>, Env, MalVal)> = None;
 
     'tco: loop {
         ret = match ast.clone() {
-            List(l, _) => {
+            List(ref l, ref meta) => {
+                let mut l = l.clone();
                 if l.len() == 0 {
                     return Ok(ast);
                 }
@@ -151,8 +252,90 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                 }
                 let a0 = &l[0];
                 match a0 {
-                    Sym(ref a0sym) if a0sym == "def!" => {
-                        env_set(&env, l[1].clone(), eval(l[2].clone(), env.clone())?)
+                    Sym(ref a0sym) if a0sym == "def!" || a0sym == "def-dynamic" => {
+                        // (def! name "doc" value) documents the value, and
+                        // (def! ^:dynamic name value), or with ^{:dynamic true}, is
+                        // (def-dynamic name value)
+                        let doc = match (l.len(), l.get(2)) {
+                            (3, _) => None,
+                            (4, Some(Str(ref doc))) if !l[2].keyword_q() => Some(doc),
+                            (4, _) => {
+                                return error(&format!("{}: docstring is not a string", a0sym))
+                            }
+                            _ => return error(&format!("{}: expected a name and a value", a0sym)),
+                        };
+                        let (name, dynamic) = match l[1] {
+                            List(ref w, _) if w.len() == 3 && w[0] == Sym("with-meta".into()) => {
+                                let dynamic = match w[2] {
+                                    Hash(ref m, _) => !matches!(
+                                        m.get("\u{29e}dynamic"),
+                                        None | Some(Nil) | Some(Bool(false))
+                                    ),
+                                    Str(ref k) => k == "\u{29e}dynamic",
+                                    _ => false,
+                                };
+                                (w[1].clone(), dynamic)
+                            }
+                            _ => (l[1].clone(), a0sym == "def-dynamic"),
+                        };
+                        let mut val = eval(l[l.len() - 1].clone(), env.clone())?;
+                        if let Sym(ref name) = name {
+                            val = val.named(name);
+                        }
+                        if let Some(doc) = doc {
+                            val = val.with_doc(doc);
+                        }
+                        match name {
+                            Sym(ref s) if dynamic && !set_dynamic(&env, s) => {
+                                error("dynamic vars are defined at the top level of a namespace")
+                            }
+                            _ => env_set(&env, name, val),
+                        }
+                    }
+                    Sym(ref a0sym) if a0sym == "binding" => {
+                        // (binding [var value ...] body) sets the vars for
+                        // the dynamic extent of the body, restoring them
+                        // however it is left
+                        let binds = match l.get(1) {
+                            Some(List(b, _)) | Some(Vector(b, _)) if b.len() % 2 == 0 => b.clone(),
+                            _ => return error("binding: expected a vector of vars and values"),
+                        };
+                        let mut vals = vec![];
+                        for (name, init) in binds.iter().tuples() {
+                            let var = match name {
+                                Sym(s) => env_var(&env, s),
+                                _ => None,
+                            };
+                            match var {
+                                Some((var_env, s)) if is_dynamic(&var_env, &s) => {
+                                    vals.push((var_env, s, eval(init.clone(), env.clone())?))
+                                }
+                                _ => {
+                                    return error(&format!(
+                                        "can't dynamically bind non-dynamic var: {}",
+                                        name.pr_str(true)
+                                    ))
+                                }
+                            }
+                        }
+                        let saved: Vec<_> = vals
+                            .into_iter()
+                            .map(|(e, s, v)| {
+                                let old = env_swap(&e, &s, v);
+                                (e, s, old)
+                            })
+                            .collect();
+                        let mut res = Ok(Nil);
+                        for form in &l[2..] {
+                            res = eval(form.clone(), env.clone());
+                            if res.is_err() {
+                                break;
+                            }
+                        }
+                        for (e, s, old) in saved.into_iter().rev() {
+                            env_swap(&e, &s, old.unwrap_or(Nil));
+                        }
+                        res
                     }
                     Sym(ref a0sym) if a0sym == "let*" => {
                         env = env_new(Some(env.clone()));
@@ -160,18 +343,8 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         match a1 {
                             List(ref binds, _) | Vector(ref binds, _) => {
                                 for (b, e) in binds.iter().tuples() {
-                                    match b {
-                                        Sym(_) => {
-                                            let _ = env_set(
-                                                &env,
-                                                b.clone(),
-                                                eval(e.clone(), env.clone())?,
-                                            );
-                                        }
-                                        _ => {
-                                            return error("let* with non-Sym binding");
-                                        }
-                                    }
+                                    let val = eval(e.clone(), env.clone())?;
+                                    env_destructure(&env, b, val, eval)?;
                                 }
                             }
                             _ => {
@@ -181,36 +354,60 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         ast = a2;
                         continue 'tco;
                     }
+                    Sym(ref a0sym) if a0sym == "loop" => {
+                        // (loop [pattern init ...] body) binds as let* does,
+                        // and a recur binds the patterns again in a fresh
+                        // frame, so closures made in an iteration keep its
+                        // values
+                        let binds = match l.get(1) {
+                            Some(List(b, _)) | Some(Vector(b, _)) if b.len() % 2 == 0 => b.clone(),
+                            _ => return error("loop: expected a vector of bindings"),
+                        };
+                        env = env_new(Some(env.clone()));
+                        for (b, e) in binds.iter().tuples() {
+                            let val = eval(e.clone(), env.clone())?;
+                            env_destructure(&env, b, val, eval)?;
+                        }
+                        ast = l.get(2).cloned().unwrap_or(Nil);
+                        recur_to = Some((binds, env.clone(), ast.clone()));
+                        continue 'tco;
+                    }
+                    Sym(ref a0sym) if a0sym == "recur" => {
+                        let (binds, loop_env, body) = match recur_to {
+                            Some((ref b, ref e, ref body)) => (b.clone(), e.clone(), body.clone()),
+                            None => return error("can only recur from tail position of a loop"),
+                        };
+                        if l.len() - 1 != binds.len() / 2 {
+                            return error(&format!(
+                                "wrong number of args ({}) passed to recur, expected {}",
+                                l.len() - 1,
+                                binds.len() / 2
+                            ));
+                        }
+                        let mut vals = vec![];
+                        for a in &l[1..] {
+                            vals.push(eval(a.clone(), env.clone())?);
+                        }
+                        env = env_new(loop_env.outer.clone());
+                        for (b, val) in binds.iter().step_by(2).zip(vals) {
+                            env_destructure(&env, b, val, eval)?;
+                        }
+                        recur_to = Some((binds, env.clone(), body.clone()));
+                        ast = body;
+                        continue 'tco;
+                    }
                     Sym(ref a0sym) if a0sym == "quote" => Ok(l[1].clone()),
-                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => Ok(quasiquote(&l[1])),
+                    Sym(ref a0sym) if a0sym == "quasiquoteexpand" => {
+                        Ok(quasiquote(&l[1], &mut FnvHashMap::default()))
+                    }
                     Sym(ref a0sym) if a0sym == "quasiquote" => {
-                        ast = quasiquote(&l[1]);
+                        ast = quasiquote(&l[1], &mut FnvHashMap::default());
                         continue 'tco;
                     }
                     Sym(ref a0sym) if a0sym == "defmacro!" => {
                         let (a1, a2) = (l[1].clone(), l[2].clone());
-                        let r = eval(a2, env.clone())?;
-                        match r {
-                            MalFunc {
-                                eval,
-                                ast,
-                                env,
-                                params,
-                                ..
-                            } => Ok(env_set(
-                                &env,
-                                a1.clone(),
-                                MalFunc {
-                                    eval: eval,
-                                    ast: ast.clone(),
-                                    env: env.clone(),
-                                    params: params.clone(),
-                                    is_macro: true,
-                                    meta: Rc::new(Nil),
-                                },
-                            )?),
-                            _ => error("set_macro on non-function"),
-                        }
+                        let f = make_macro(eval(a2, env.clone())?, &a1)?;
+                        env_set(&env, a1, f)
                     }
                     Sym(ref a0sym) if a0sym == "macroexpand" => {
                         match macroexpand(l[1].clone(), &env) {
@@ -225,7 +422,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                 ErrString(s) => Str(s.to_string()),
                             };
                             match l[2].clone() {
-                                List(c, _) => {
+                                List(ref c, _) => {
                                     let catch_env = env_bind(
                                         Some(env.clone()),
                                         list!(vec![c[1].clone()]),
@@ -238,6 +435,17 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         }
                         res => res,
                     },
+                    Sym(ref a0sym) if a0sym == "do" && l.len() == 1 => Ok(Nil),
+                    Sym(ref a0sym) if a0sym == "do" && env.ns.is_some() => {
+                        // a top-level do evaluates each form in the namespace
+                        // current by then, so that a loaded file can use ns
+                        for form in &l[1..l.len() - 1] {
+                            eval(form.clone(), current_ns().unwrap_or(env.clone()))?;
+                        }
+                        env = current_ns().unwrap_or(env);
+                        ast = l.last().unwrap_or(&Nil).clone();
+                        continue 'tco;
+                    }
                     Sym(ref a0sym) if a0sym == "do" => {
                         match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
                             List(_, _) => {
@@ -263,39 +471,66 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         }
                     }
                     Sym(ref a0sym) if a0sym == "fn*" => {
-                        let (a1, a2) = (l[1].clone(), l[2].clone());
+                        // the body is analyzed on creation, unless it was
+                        // along with an enclosing function
+                        if !analyzer::is_analyzed(meta) && l.len() >= 2 {
+                            if let List(ref analyzed, _) = analyzer::analyze_fn(&l, &env) {
+                                l = analyzed.clone();
+                            }
+                        }
+                        let (doc, params, a2) = fn_parts(&l);
+                        // with the VM, function bodies are compiled to run
+                        let a2 = if vm::enabled() {
+                            vm::deferred(&params, a2)
+                        } else {
+                            a2
+                        };
                         Ok(MalFunc {
                             eval: eval,
                             ast: Rc::new(a2),
                             env: env,
-                            params: Rc::new(a1),
+                            params: Rc::new(params),
                             is_macro: false,
                             meta: Rc::new(Nil),
+                            info: Rc::new(FnInfo {
+                                doc,
+                                ..Default::default()
+                            }),
                         })
                     }
                     Sym(ref a0sym) if a0sym == "eval" => {
                         ast = eval(l[1].clone(), env.clone())?;
-                        while let Some(ref e) = env.clone().outer {
-                            env = e.clone();
+                        recur_to = None;
+                        // (eval form env) evaluates in the given env
+                        if l.len() > 2 {
+                            match eval(l[2].clone(), env.clone())? {
+                                MalEnv(ref e) => env = e.clone(),
+                                _ => return error("eval: expected an environment"),
+                            }
+                            continue 'tco;
+                        }
+                        // otherwise at the top level: in the current namespace
+                        // when called from one
+                        while let (None, Some(e)) = (&env.ns, env.outer.clone()) {
+                            env = e;
+                        }
+                        if env.ns.is_some() {
+                            env = current_ns().unwrap_or(env);
                         }
                         continue 'tco;
                     }
+                    Sym(ref a0sym) if a0sym == "current-env" => Ok(MalEnv(env.clone())),
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
                             let ref f = el[0].clone();
                             let args = el[1..].to_vec();
                             match f {
-                                Func(_, _) => f.apply(args),
-                                MalFunc {
-                                    ast: mast,
-                                    env: menv,
-                                    params,
-                                    ..
-                                } => {
-                                    let a = &**mast;
-                                    let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
+                                Func(..) => f.apply(args),
+                                MalFunc { env: menv, .. } => {
+                                    let (p, a) = f.clause(args.len())?;
+                                    env = env_bind_args(Some(menv.clone()), p, args, eval)?;
                                     ast = a.clone();
+                                    recur_to = None;
                                     continue 'tco;
                                 }
                                 _ => error("attempt to call non-function"),
@@ -305,6 +540,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     },
                 }
             }
+            Code(ref code) => vm::run(code, env.clone()),
             _ => eval_ast(&ast, &env),
         };
 
@@ -315,8 +551,21 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
 }
 
 // print
+fn repl_color() -> bool {
+    !matches!(
+        repl_env_get("*repl-color*"),
+        None | Some(Nil) | Some(Bool(false))
+    )
+}
+
 fn print(ast: &MalVal) -> String {
-    ast.pr_str(true)
+    // results are pretty printed when *repl-pprint* is true (or a width)
+    let width = match repl_env_get("*repl-pprint*") {
+        Some(Int(w)) if w > 0 => Some(w as usize),
+        None | Some(Nil) | Some(Bool(false)) => None,
+        Some(_) => Some(80),
+    };
+    repl_str(ast, width, repl_color())
 }
 
 fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
@@ -326,6 +575,45 @@ fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
 }
 
 fn main() {
+    // the interpreter runs on a thread with stack enough for its depth
+    // limit (MAL_MAX_DEPTH levels of eval), so that recursing too deeply
+    // is an error rather than a native stack overflow
+    let max_depth = std::env::var("MAL_MAX_DEPTH")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_MAX_DEPTH);
+    let stack_size = match max_depth
+        .checked_mul(STACK_PER_LEVEL)
+        .and_then(|n| n.checked_add(16 << 20))
+    {
+        Some(n) => n,
+        None => {
+            eprintln!("MAL_MAX_DEPTH {} is too large", max_depth);
+            std::process::exit(1);
+        }
+    };
+    let interpreter = std::thread::Builder::new()
+        .stack_size(stack_size)
+        .spawn(move || {
+            set_stack_limits(max_depth, stack_size);
+            repl()
+        });
+    let interpreter = match interpreter {
+        Ok(interpreter) => interpreter,
+        Err(e) => {
+            eprintln!(
+                "cannot start the interpreter with a stack of {} bytes for MAL_MAX_DEPTH {}: {}",
+                stack_size, max_depth, e
+            );
+            std::process::exit(1);
+        }
+    };
+    if interpreter.join().is_err() {
+        std::process::exit(1);
+    }
+}
+
+fn repl() {
     let mut args = std::env::args();
     let arg1 = args.nth(1);
 
@@ -336,20 +624,46 @@ fn main() {
     }
 
     // core.rs: defined using rust
-    let repl_env = env_new(None);
+    // the builtins are in the mal.core namespace, which every namespace
+    // looks missing names up in
+    let core_env = env_new_ns(&Symbol::new("mal.core"), None);
     for (k, v) in core::ns() {
-        env_sets(&repl_env, k, v);
+        env_sets(&core_env, k, v);
+    }
+    env_sets(&core_env, "*ARGV*", list!(args.map(Str).collect()));
+    // colour REPL output only on a terminal, unless NO_COLOR is set
+    let color = std::io::stdout().is_terminal()
+        && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        && std::env::var("TERM").map_or(true, |t| t != "dumb");
+    env_sets(&core_env, "*repl-color*", Bool(color));
+    set_dynamic(&core_env, &Symbol::new("*repl-color*"));
+    set_core_env(&core_env);
+    // MAL_VM=1 runs functions on the bytecode VM
+    if std::env::var_os("MAL_VM").is_some_and(|v| !v.is_empty() && v != "0") {
+        vm::enable();
     }
-    env_sets(&repl_env, "*ARGV*", list!(args.map(Str).collect()));
 
     // core.mal: defined using the language itself
-    let _ = rep("(def! *host-language* \"rust\")", &repl_env);
-    let _ = rep("(def! not (fn* (a) (if a false true)))", &repl_env);
+    let _ = rep("(def! *host-language* \"rust\")", &core_env);
+    let _ = rep("(def-dynamic *data-readers* {})", &core_env);
+    let _ = rep("(def-dynamic *default-data-reader-fn* nil)", &core_env);
+    let _ = rep("(def-dynamic *print-length* nil)", &core_env);
+    let _ = rep("(def-dynamic *print-level* nil)", &core_env);
+    let _ = rep("(def-dynamic *repl-pprint* false)", &core_env);
     let _ = rep(
-        "(def! load-file (fn* (f) (eval (read-string (str \"(do \" (slurp f) \"\nnil)\")))))",
-        &repl_env,
+        "(def! not (fn* (a) \"Returns true if a is logically false\" (if a false true)))",
+        &core_env,
     );
-    let _ = rep("(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &repl_env);
+    let _ = rep(
+        "(def! load-file (fn* (f) \"Reads and evaluates the forms in the file f\" (eval (read-file f))))",
+        &core_env,
+    );
+    let _ = rep("(defmacro! cond (fn* (& xs) \"Evaluates the expr of the first test that is truthy\" (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw \"odd number of forms to cond\")) (cons 'cond (rest (rest xs)))))))", &core_env);
+    let _ = rep("(defmacro! ns (fn* (name & clauses) \"Switches to the namespace name, creating it, and requires the specs of its (:require ...) clauses\" `(do (in-ns '~name) ~@(map (fn* (c) (if (= (first c) :require) `(require ~@(map (fn* (s) (list 'quote s)) (rest c))) (throw (str \"ns: unsupported clause \" (first c))))) clauses) nil)))", &core_env);
+
+    // the REPL starts in the user namespace
+    let repl_env = env_new_ns(&Symbol::new("user"), Some(core_env.clone()));
+    set_current_ns(&repl_env);
 
     // Invoked with arguments
     if let Some(f) = arg1 {
@@ -371,8 +685,11 @@ fn main() {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
                 if line.len() > 0 {
-                    match rep(&line, &repl_env) {
+                    match rep(&line, &current_ns().unwrap_or(repl_env.clone())) {
                         Ok(out) => println!("{}", out),
+                        Err(e) if repl_color() => {
+                            println!("{}Error: {}{}", ERROR, format_error(e), RESET)
+                        }
                         Err(e) => println!("Error: {}", format_error(e)),
                     }
                 }
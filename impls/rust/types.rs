@@ -1,12 +1,18 @@
-use std::cell::RefCell;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::hash::Hasher;
+use std::ops::Deref;
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvHashMap, FnvHashSet};
 use itertools::Itertools;
 
-use crate::env::{env_bind, Env};
+use crate::env::{env_bind_args, Env};
 use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, Bool, Func, Hash, Int, List, MalEnv, MalFunc, Nil, Str, Sym, Vector,
+};
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
@@ -15,11 +21,15 @@ pub enum MalVal {
     Int(i64),
     //Float(f64),
     Str(String),
-    Sym(String),
+    Sym(Symbol),
+    // a local in a function body resolved by the analyzer: its name and
+    // the slot it is bound in, in the frame depth levels up
+    #[allow(dead_code)] // only the stepA analyzer resolves locals
+    Local(Symbol, usize, usize),
     List(Rc<Vec<MalVal>>, Rc<MalVal>),
     Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
     Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    Func(fn(MalArgs) -> MalRet, Rc<MalVal>, Rc<FnInfo>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
         ast: Rc<MalVal>,
@@ -27,8 +37,127 @@ pub enum MalVal {
         params: Rc<MalVal>,
         is_macro: bool,
         meta: Rc<MalVal>,
+        info: Rc<FnInfo>,
     },
     Atom(Rc<RefCell<MalVal>>),
+    // An environment as a value holds it strongly, so storing one where
+    // it can reach itself, e.g. (def! e *ENV*), makes a cycle that is
+    // never freed. That costs nothing for a top-level environment, which
+    // lives as long as the REPL, but leaks a frame per call when done
+    // inside a function.
+    MalEnv(Env),
+    // a function body for the stepA bytecode VM, in place of its ast
+    #[allow(dead_code)] // only the stepA VM compiles bodies
+    Code(Compiled),
+}
+
+// Opaque here: only the VM knows what it compiled
+#[derive(Clone)]
+#[allow(dead_code)] // opened by the stepA VM only
+pub struct Compiled(pub Rc<dyn Any>);
+
+impl fmt::Debug for Compiled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("#<code>")
+    }
+}
+
+// Symbol names are interned: all symbols with the same name share one
+// string, so symbols compare and hash by pointer (e.g. as env keys).
+#[derive(Clone)]
+pub struct Symbol(Rc<str>);
+
+thread_local! {
+    static SYMBOLS: RefCell<FnvHashSet<Rc<str>>> = RefCell::new(FnvHashSet::default());
+}
+
+impl Symbol {
+    pub fn new(name: &str) -> Symbol {
+        SYMBOLS.with(|t| {
+            let mut t = t.borrow_mut();
+            if let Some(s) = t.get(name) {
+                return Symbol(s.clone());
+            }
+            let s: Rc<str> = Rc::from(name);
+            t.insert(s.clone());
+            Symbol(s)
+        })
+    }
+}
+
+// A name is removed from the table with its last symbol, so that
+// gensyms don't accumulate
+impl Drop for Symbol {
+    fn drop(&mut self) {
+        if Rc::strong_count(&self.0) == 2 {
+            let _ = SYMBOLS.try_with(|t| {
+                if let Ok(mut t) = t.try_borrow_mut() {
+                    t.remove(&*self.0);
+                }
+            });
+        }
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Symbol {
+        Symbol::new(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Symbol {
+        Symbol::new(&name)
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl std::hash::Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (Rc::as_ptr(&self.0) as *const u8).hash(state)
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// The name (given by def!), docstring and argument lists of a function,
+// for doc and the printer. Kept apart from the metadata of with-meta.
+// The arglists of a mal function are its params.
+#[derive(Debug, Clone, Default)]
+pub struct FnInfo {
+    pub name: Option<String>,
+    pub doc: Option<String>,
+    pub arglists: Option<MalVal>,
 }
 
 #[derive(Debug)]
@@ -68,6 +197,61 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+// Nesting of eval and the reader is counted, so that going too deep is
+// an error rather than a native stack overflow. As what a level costs
+// varies, the stack left is checked too where its size is known.
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static MAX_DEPTH: Cell<usize> = const { Cell::new(DEFAULT_MAX_DEPTH) };
+    // the address the stack must not go below, 0 when unknown
+    static STACK_END: Cell<usize> = const { Cell::new(0) };
+}
+
+pub const DEFAULT_MAX_DEPTH: usize = 10000;
+
+// The stack a level of eval takes, at most: measured at about 32K in a
+// debug build and 4K in a release one, doubled
+#[allow(dead_code)] // only stepA sizes the stack of its thread
+pub const STACK_PER_LEVEL: usize = if cfg!(debug_assertions) {
+    64 << 10
+} else {
+    8 << 10
+};
+
+// The stack kept for what runs between two levels, e.g. a builtin
+const STACK_RESERVE: usize = 1 << 20;
+
+// Limits the current thread to max_depth levels, within the size bytes
+// of stack it has from here down
+#[allow(dead_code)] // the other steps run with the default limits
+pub fn set_stack_limits(max_depth: usize, size: usize) {
+    let here = 0u8;
+    let end = (&here as *const u8 as usize).saturating_sub(size);
+    MAX_DEPTH.with(|m| m.set(max_depth));
+    STACK_END.with(|e| e.set(end.saturating_add(STACK_RESERVE)))
+}
+
+// One level of nesting, left when dropped
+pub struct Depth(());
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1))
+    }
+}
+
+pub fn enter_depth() -> Result<Depth, MalErr> {
+    let n = DEPTH.with(|d| d.get());
+    let here = 0u8;
+    if n >= MAX_DEPTH.with(|m| m.get())
+        || (&here as *const u8 as usize) < STACK_END.with(|e| e.get())
+    {
+        return Err(ErrString("stack depth exceeded".to_string()));
+    }
+    DEPTH.with(|d| d.set(n + 1));
+    Ok(Depth(()))
+}
+
 pub fn format_error(e: MalErr) -> String {
     match e {
         ErrString(s) => s.clone(),
@@ -106,17 +290,10 @@ impl MalVal {
 
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
-            Func(f, _) => f(args),
-            MalFunc {
-                eval,
-                ref ast,
-                ref env,
-                ref params,
-                ..
-            } => {
-                let a = &**ast;
-                let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
+            Func(f, ..) => f(args),
+            MalFunc { eval, ref env, .. } => {
+                let (p, a) = self.clause(args.len())?;
+                let fn_env = env_bind_args(Some(env.clone()), p, args, eval)?;
                 Ok(eval(a.clone(), fn_env)?)
             }
             _ => error("attempt to call non-function"),
@@ -163,7 +340,7 @@ impl MalVal {
     pub fn get_meta(&self) -> MalRet {
         match self {
             List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
-            Func(_, meta) => Ok((&**meta).clone()),
+            Func(_, meta, _) => Ok((&**meta).clone()),
             MalFunc { meta, .. } => Ok((&**meta).clone()),
             _ => error("meta not supported by type"),
         }
@@ -174,7 +351,7 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
-            | Func(_, ref mut meta)
+            | Func(_, ref mut meta, _)
             | MalFunc { ref mut meta, .. } => {
                 *meta = Rc::new((&*new_meta).clone());
             }
@@ -182,29 +359,233 @@ impl MalVal {
         };
         Ok(self.clone())
     }
+
+    pub fn fn_info(&self) -> Option<&FnInfo> {
+        match self {
+            Func(_, _, info) | MalFunc { info, .. } => Some(info),
+            _ => None,
+        }
+    }
+
+    // The function with its name set, unless it already has one
+    #[allow(dead_code)] // only the stepA def! names functions
+    pub fn named(&self, name: &str) -> MalVal {
+        let mut f = self.clone();
+        if let Func(_, _, ref mut info) | MalFunc { ref mut info, .. } = f {
+            if info.name.is_none() {
+                Rc::make_mut(info).name = Some(name.to_string());
+            }
+        }
+        f
+    }
+
+    #[allow(dead_code)] // docstrings are read by the stepA fn* only
+    pub fn with_doc(&self, doc: &str) -> MalVal {
+        let mut f = self.clone();
+        if let Func(_, _, ref mut info) | MalFunc { ref mut info, .. } = f {
+            Rc::make_mut(info).doc = Some(doc.to_string());
+        }
+        f
+    }
+
+    // The params and body a function call with n args evaluates: the
+    // function's own, or those of the clause of a multi-arity function
+    // (one with nil params) for n, a fixed arity before a variadic one
+    pub fn clause(&self, n: usize) -> Result<(&MalVal, &MalVal), MalErr> {
+        let (params, ast, info) = match self {
+            MalFunc {
+                params, ast, info, ..
+            } => (&**params, &**ast, info),
+            _ => return Err(ErrString("attempt to call non-function".to_string())),
+        };
+        let found = match (params, ast) {
+            (Nil, List(clauses, _)) => {
+                let clauses = clauses.iter().filter_map(|c| match c {
+                    List(c, _) => Some((&c[0], &c[1])),
+                    _ => None,
+                });
+                let mut variadic = None;
+                let mut found = None;
+                for (p, a) in clauses {
+                    match arity(p) {
+                        (req, false) if req == n => {
+                            found = Some((p, a));
+                            break;
+                        }
+                        (req, true) if req <= n && variadic.is_none() => variadic = Some((p, a)),
+                        _ => {}
+                    }
+                }
+                found.or(variadic)
+            }
+            _ => match arity(params) {
+                (req, variadic) if req == n || (variadic && req < n) => Some((params, ast)),
+                _ => None,
+            },
+        };
+        found.ok_or_else(|| {
+            ErrString(format!(
+                "wrong number of args ({}) passed to {}",
+                n,
+                info.name.as_deref().unwrap_or("anonymous")
+            ))
+        })
+    }
+
+    // A list of the argument vectors of a function, nil when unknown
+    pub fn arglists(&self) -> MalRet {
+        match self {
+            MalFunc { params, ast, .. } => match (&**params, &**ast) {
+                (List(ref p, _), _) | (Vector(ref p, _), _) => Ok(list!(vec![vector!(p.to_vec())])),
+                (Nil, List(ref clauses, _)) => Ok(list!(clauses
+                    .iter()
+                    .filter_map(|c| match c {
+                        List(c, _) => Some(c[0].clone()),
+                        _ => None,
+                    })
+                    .collect())),
+                _ => Ok(Nil),
+            },
+            Func(_, _, info) => Ok(info.arglists.clone().unwrap_or(Nil)),
+            _ => error("arglists of a non-function"),
+        }
+    }
 }
 
+// Iterative, so that deeply nested values compare without overflowing
+// the stack: pairs holds the elements left to compare
 impl PartialEq for MalVal {
     fn eq(&self, other: &MalVal) -> bool {
-        match (self, other) {
-            (Nil, Nil) => true,
-            (Bool(ref a), Bool(ref b)) => a == b,
-            (Int(ref a), Int(ref b)) => a == b,
-            (Str(ref a), Str(ref b)) => a == b,
-            (Sym(ref a), Sym(ref b)) => a == b,
-            (List(ref a, _), List(ref b, _))
-            | (Vector(ref a, _), Vector(ref b, _))
-            | (List(ref a, _), Vector(ref b, _))
-            | (Vector(ref a, _), List(ref b, _)) => a == b,
-            (Hash(ref a, _), Hash(ref b, _)) => a == b,
-            (MalFunc { .. }, MalFunc { .. }) => false,
-            _ => false,
+        let mut pairs = vec![(self, other)];
+        while let Some(pair) = pairs.pop() {
+            let same = match pair {
+                (Nil, Nil) => true,
+                (Bool(ref a), Bool(ref b)) => a == b,
+                (Int(ref a), Int(ref b)) => a == b,
+                (Str(ref a), Str(ref b)) => a == b,
+                (Sym(ref a), Sym(ref b)) => a == b,
+                (List(ref a, _), List(ref b, _))
+                | (Vector(ref a, _), Vector(ref b, _))
+                | (List(ref a, _), Vector(ref b, _))
+                | (Vector(ref a, _), List(ref b, _)) => {
+                    pairs.extend(a.iter().zip(b.iter()));
+                    a.len() == b.len()
+                }
+                (Hash(ref a, _), Hash(ref b, _)) => {
+                    a.len() == b.len()
+                        && a.iter().all(|(k, v)| match b.get(k) {
+                            Some(w) => {
+                                pairs.push((v, w));
+                                true
+                            }
+                            None => false,
+                        })
+                }
+                // atoms are mutable references so compare by identity (this
+                // also keeps = terminating on atoms that contain themselves)
+                (Atom(ref a), Atom(ref b)) => Rc::ptr_eq(a, b),
+                (MalEnv(ref a), MalEnv(ref b)) => Rc::ptr_eq(a, b),
+                (MalFunc { .. }, MalFunc { .. }) => false,
+                _ => false,
+            };
+            if !same {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// Iterative too, so that a deeply nested collection, e.g. a list of a
+// list of ... built in a loop, is freed without overflowing the stack:
+// held gathers the values left to drop that would free values in turn
+impl Drop for MalVal {
+    #[inline]
+    fn drop(&mut self) {
+        if let List(..) | Vector(..) | Hash(..) | Atom(_) = self {
+            drop_nested(self)
+        }
+    }
+}
+
+fn drop_nested(v: &mut MalVal) {
+    if nests(v) {
+        let mut held = vec![];
+        take_held(v, &mut held);
+        while let Some(mut v) = held.pop() {
+            take_held(&mut v, &mut held);
+        }
+    }
+}
+
+// Whether dropping v frees the values of a collection or atom that only
+// it holds
+fn frees_values(v: &MalVal) -> bool {
+    match v {
+        List(l, _) | Vector(l, _) => Rc::strong_count(l) == 1 && !l.is_empty(),
+        Hash(h, _) => Rc::strong_count(h) == 1 && !h.is_empty(),
+        Atom(a) => Rc::strong_count(a) == 1,
+        _ => false,
+    }
+}
+
+// Whether dropping v frees a value that frees values in turn, the case
+// where it would recurse
+fn nests(v: &MalVal) -> bool {
+    match v {
+        List(l, _) | Vector(l, _) => Rc::strong_count(l) == 1 && l.iter().any(frees_values),
+        Hash(h, _) => Rc::strong_count(h) == 1 && h.values().any(frees_values),
+        Atom(a) => Rc::strong_count(a) == 1 && a.try_borrow().is_ok_and(|v| frees_values(&v)),
+        _ => false,
+    }
+}
+
+// Moves into held the values that only v holds and that free values
+fn take_held(v: &mut MalVal, held: &mut Vec<MalVal>) {
+    let mut take = |v: &mut MalVal| {
+        if frees_values(v) {
+            held.push(std::mem::replace(v, Nil));
+        }
+    };
+    match v {
+        List(l, _) | Vector(l, _) => {
+            if let Some(l) = Rc::get_mut(l) {
+                l.iter_mut().for_each(take);
+            }
+        }
+        Hash(h, _) => {
+            if let Some(h) = Rc::get_mut(h) {
+                h.values_mut().for_each(take);
+            }
+        }
+        Atom(a) => {
+            if let Some(a) = Rc::get_mut(a) {
+                take(a.get_mut());
+            }
+        }
+        _ => {}
+    }
+}
+
+// The number of args params require, and whether they take more: a
+// & rest or an :as ends the fixed params
+pub fn arity(params: &MalVal) -> (usize, bool) {
+    let p = match params {
+        List(p, _) | Vector(p, _) => p,
+        _ => return (0, true),
+    };
+    for (i, x) in p.iter().enumerate() {
+        match x {
+            Sym(s) if s == "&" => return (i, true),
+            Str(s) if s == "\u{29e}as" => return (i, false),
+            _ => {}
         }
     }
+    (p.len(), false)
 }
 
 pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
+    Func(f, Rc::new(Nil), Rc::default())
 }
 
 pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
@@ -0,0 +1,677 @@
+// The bytecode backend, enabled with MAL_VM=1. Function bodies are
+// compiled when first called, with the macros of the time expanded, to
+// code for a stack machine (and compiled again when called after a macro
+// is defined, so as to expand the macros as eval would): a call of a compiled function from compiled
+// code pushes a frame rather than recursing, and a call in tail position
+// replaces the frame. Locals stay in env frames, laid out as by eval and
+// addressed by the analyzer, so compiled and evaluated code mix freely:
+// the forms not compiled (e.g. binding or eval) are evaluated by eval in
+// the current env.
+
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::rc::Rc;
+
+use fnv::FnvHashMap;
+
+use crate::env::{env_bind_args, env_destructure, env_get, env_get_local, env_new, env_set, Env};
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{Code, Func, Hash, List, Local, MalEnv, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::{enter_depth, Compiled, Depth, FnInfo, MalErr, MalRet, MalVal, Symbol};
+use crate::{analyzer, eval, fn_parts, macroexpand, make_macro, quasiquote};
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    // bumped whenever a macro is defined, to recompile the code compiled
+    // with the macros before
+    static MACROS: Cell<u64> = const { Cell::new(0) };
+}
+
+pub fn enable() {
+    ENABLED.with(|e| e.set(true))
+}
+
+pub fn enabled() -> bool {
+    ENABLED.with(|e| e.get())
+}
+
+pub fn macros_changed() {
+    MACROS.with(|m| m.set(m.get() + 1))
+}
+
+enum Op {
+    Const(MalVal),
+    Global(MalVal),
+    Local(Symbol, usize, usize),
+    Pop,
+    Jump(usize),
+    JumpIfFalse(usize),
+    Call(usize),
+    TailCall(usize),
+    Return,
+    Closure(Rc<Template>),
+    Def(MalVal, Option<String>),
+    DefMacro(MalVal),
+    Vector(usize),
+    Hash(Vec<String>),
+    // a frame for let* or catch*, and a binding in it
+    EnterFrame,
+    Bind(MalVal),
+    LeaveFrame,
+    // an error from here to EndTry jumps to the handler
+    Try(usize),
+    EndTry,
+    Recur(Rc<Loop>),
+    Fail(String),
+    Eval(MalVal),
+}
+
+// Where a recur goes: the patterns of the loop, bound again in a fresh
+// frame in place of its own (the depth-th one entered) before starting
+// over at its body
+struct Loop {
+    patterns: Vec<MalVal>,
+    depth: usize,
+    start: usize,
+}
+
+struct Chunk {
+    code: Vec<Op>,
+}
+
+// What the closures of a fn* are made of
+struct Template {
+    params: Rc<MalVal>,
+    ast: Rc<MalVal>,
+    info: Rc<FnInfo>,
+}
+
+// A function body, compiled when first run with the macros then defined
+struct Proto {
+    body: MalVal,
+    chunk: RefCell<Option<(u64, Rc<Chunk>)>>,
+}
+
+impl Proto {
+    fn chunk(&self, env: &Env) -> Rc<Chunk> {
+        let macros = MACROS.with(|m| m.get());
+        if let Some((compiled, ref chunk)) = *self.chunk.borrow() {
+            if compiled == macros {
+                return chunk.clone();
+            }
+        }
+        let chunk = Rc::new(Compiler::new(env).body(&self.body));
+        *self.chunk.borrow_mut() = Some((macros, chunk.clone()));
+        chunk
+    }
+}
+
+// The ast of a function made of a fn* form: its body, or the body of
+// each clause, to be compiled when first run
+pub fn deferred(params: &MalVal, ast: MalVal) -> MalVal {
+    let code = |body: &MalVal| {
+        Code(Compiled(Rc::new(Proto {
+            body: body.clone(),
+            chunk: RefCell::new(None),
+        })))
+    };
+    match (params, &ast) {
+        (Nil, List(clauses, _)) => list!(clauses
+            .iter()
+            .map(|c| match c {
+                List(c, _) => list![c[0].clone(), code(&c[1])],
+                _ => c.clone(),
+            })
+            .collect()),
+        _ => code(&ast),
+    }
+}
+
+fn proto(code: &Compiled) -> &Proto {
+    code.0.downcast_ref().expect("code compiled by the VM")
+}
+
+struct Compiler<'a> {
+    code: Vec<Op>,
+    // the env of the call compiling it, for the macros
+    env: &'a Env,
+    // the let*, loop and catch* frames entered
+    depth: usize,
+    // the loop a recur here would go to, in tail position of one
+    recur: Option<Rc<Loop>>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(env: &'a Env) -> Compiler<'a> {
+        Compiler {
+            code: vec![],
+            env,
+            depth: 0,
+            recur: None,
+        }
+    }
+
+    fn body(mut self, ast: &MalVal) -> Chunk {
+        self.expr(ast, true);
+        Chunk { code: self.code }
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    // Point the jump emitted at at to the next op
+    fn patch(&mut self, at: usize) {
+        let to = self.code.len();
+        match self.code[at] {
+            Op::Jump(ref mut pc) | Op::JumpIfFalse(ref mut pc) | Op::Try(ref mut pc) => *pc = to,
+            _ => unreachable!(),
+        }
+    }
+
+    // An expr in tail position returns its value, or tail calls
+    fn expr(&mut self, ast: &MalVal, tail: bool) {
+        let op = match ast {
+            Sym(_) => Op::Global(ast.clone()),
+            Local(name, depth, slot) => Op::Local(name.clone(), *depth, *slot),
+            List(l, _) if !l.is_empty() => return self.list(ast, l, tail),
+            Vector(v, _) => {
+                for x in v.iter() {
+                    self.value(x);
+                }
+                Op::Vector(v.len())
+            }
+            Hash(hm, _) => {
+                let mut keys = vec![];
+                for (k, v) in hm.iter() {
+                    keys.push(k.to_string());
+                    self.value(v);
+                }
+                Op::Hash(keys)
+            }
+            _ => Op::Const(ast.clone()),
+        };
+        self.emit(op);
+        self.ret(tail);
+    }
+
+    // An expr whose value is used: not in tail position
+    fn value(&mut self, ast: &MalVal) {
+        let recur = self.recur.take();
+        self.expr(ast, false);
+        self.recur = recur;
+    }
+
+    // The body of a let*, loop or catch* in a frame of its own
+    fn enter_frame(&mut self) {
+        self.emit(Op::EnterFrame);
+        self.depth += 1;
+    }
+
+    fn leave_frame(&mut self, tail: bool) {
+        if !tail {
+            self.emit(Op::LeaveFrame);
+        }
+        self.depth -= 1;
+    }
+
+    fn ret(&mut self, tail: bool) {
+        if tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    // A form left to eval
+    fn fallback(&mut self, ast: &MalVal, tail: bool) {
+        self.emit(Op::Eval(ast.clone()));
+        self.ret(tail);
+    }
+
+    fn list(&mut self, ast: &MalVal, l: &[MalVal], tail: bool) {
+        match macroexpand(ast.clone(), self.env) {
+            (true, Ok(new_ast)) => return self.expr(&new_ast, tail),
+            // eval fails the same way, should the form be reached
+            (_, Err(_)) => return self.fallback(ast, tail),
+            _ => {}
+        }
+        let form = match l[0] {
+            Sym(ref s) => &s[..],
+            _ => "",
+        };
+        match form {
+            "def!" => match (l.len(), &l[1], l.get(2)) {
+                (3, Sym(_), _) => {
+                    self.value(&l[2]);
+                    self.emit(Op::Def(l[1].clone(), None));
+                }
+                (4, Sym(_), Some(Str(doc))) if !l[2].keyword_q() => {
+                    self.value(&l[3]);
+                    self.emit(Op::Def(l[1].clone(), Some(doc.to_string())));
+                }
+                _ => return self.fallback(ast, tail),
+            },
+            "defmacro!" if l.len() == 3 => {
+                self.value(&l[2]);
+                self.emit(Op::DefMacro(l[1].clone()));
+            }
+            "let*" if l.len() >= 2 => {
+                let binds = match l[1] {
+                    List(ref b, _) | Vector(ref b, _) => b,
+                    _ => return self.fallback(ast, tail),
+                };
+                self.enter_frame();
+                for pair in binds.chunks_exact(2) {
+                    self.value(&pair[1]);
+                    self.emit(Op::Bind(pair[0].clone()));
+                }
+                self.expr(l.get(2).unwrap_or(&Nil), tail);
+                self.leave_frame(tail);
+                return;
+            }
+            "loop" => {
+                let binds = match l.get(1) {
+                    Some(List(b, _)) | Some(Vector(b, _)) if b.len() % 2 == 0 => b,
+                    _ => return self.fallback(ast, tail),
+                };
+                self.enter_frame();
+                for pair in binds.chunks_exact(2) {
+                    self.value(&pair[1]);
+                    self.emit(Op::Bind(pair[0].clone()));
+                }
+                let target = Loop {
+                    patterns: binds.iter().step_by(2).cloned().collect(),
+                    depth: self.depth,
+                    start: self.code.len(),
+                };
+                let recur = self.recur.replace(Rc::new(target));
+                self.expr(l.get(2).unwrap_or(&Nil), tail);
+                self.recur = recur;
+                self.leave_frame(tail);
+                return;
+            }
+            // the checks eval makes as it reaches the recur
+            "recur" => {
+                let target = match self.recur {
+                    Some(ref target) => target.clone(),
+                    None => {
+                        let msg = "can only recur from tail position of a loop";
+                        self.emit(Op::Fail(msg.to_string()));
+                        return;
+                    }
+                };
+                if l.len() - 1 != target.patterns.len() {
+                    self.emit(Op::Fail(format!(
+                        "wrong number of args ({}) passed to recur, expected {}",
+                        l.len() - 1,
+                        target.patterns.len()
+                    )));
+                    return;
+                }
+                for x in &l[1..] {
+                    self.value(x);
+                }
+                self.emit(Op::Recur(target));
+                return;
+            }
+            "quote" if l.len() >= 2 => {
+                self.emit(Op::Const(l[1].clone()));
+            }
+            // the foo# of a quasiquote are generated afresh each time
+            "quasiquote" if l.len() >= 2 => {
+                let mut gensyms = FnvHashMap::default();
+                let expanded = quasiquote(&l[1], &mut gensyms);
+                if !gensyms.is_empty() {
+                    return self.fallback(ast, tail);
+                }
+                return self.expr(&expanded, tail);
+            }
+            "do" => {
+                if l.len() == 1 {
+                    self.emit(Op::Const(Nil));
+                } else {
+                    for form in &l[1..l.len() - 1] {
+                        self.value(form);
+                        self.emit(Op::Pop);
+                    }
+                    return self.expr(&l[l.len() - 1], tail);
+                }
+            }
+            "if" if l.len() >= 2 => {
+                self.value(&l[1]);
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.expr(l.get(2).unwrap_or(&Nil), tail);
+                let to_end = if tail {
+                    None
+                } else {
+                    Some(self.emit(Op::Jump(0)))
+                };
+                self.patch(to_else);
+                self.expr(l.get(3).unwrap_or(&Nil), tail);
+                if let Some(to_end) = to_end {
+                    self.patch(to_end);
+                }
+                return;
+            }
+            "fn*" if l.len() >= 2 => {
+                let analyzed;
+                let l = match ast {
+                    List(_, meta) if analyzer::is_analyzed(meta) => l,
+                    _ => match analyzer::analyze_fn(l, self.env) {
+                        List(ref a, _) => {
+                            analyzed = a.clone();
+                            &analyzed[..]
+                        }
+                        _ => unreachable!(),
+                    },
+                };
+                let (doc, params, body) = fn_parts(l);
+                self.emit(Op::Closure(Rc::new(Template {
+                    ast: Rc::new(deferred(&params, body)),
+                    params: Rc::new(params),
+                    info: Rc::new(FnInfo {
+                        doc,
+                        ..Default::default()
+                    }),
+                })));
+            }
+            "try*" if l.len() >= 3 => {
+                let catch = match l[2] {
+                    List(ref c, _) if c.len() >= 3 => c,
+                    _ => return self.fallback(ast, tail),
+                };
+                let to_handler = self.emit(Op::Try(0));
+                self.value(&l[1]);
+                self.emit(Op::EndTry);
+                let to_end = self.emit(Op::Jump(0));
+                // the handler starts with the exception pushed
+                self.patch(to_handler);
+                self.enter_frame();
+                self.emit(Op::Bind(catch[1].clone()));
+                self.value(&catch[2]);
+                self.leave_frame(false);
+                self.patch(to_end);
+            }
+            "try*" if l.len() == 2 => self.value(&l[1]),
+            "def-dynamic" | "binding" | "eval" | "current-env" | "macroexpand"
+            | "quasiquoteexpand" | "quote" | "quasiquote" | "let*" | "if" | "fn*" | "defmacro!"
+            | "try*" => return self.fallback(ast, tail),
+            _ => {
+                for x in l {
+                    self.value(x);
+                }
+                let argc = l.len() - 1;
+                self.emit(if tail {
+                    Op::TailCall(argc)
+                } else {
+                    Op::Call(argc)
+                });
+                return;
+            }
+        }
+        self.ret(tail);
+    }
+}
+
+// The state of a compiled function while it calls another, counted
+// towards the depth limit as eval is
+struct Frame {
+    _depth: Depth,
+    chunk: Rc<Chunk>,
+    pc: usize,
+    env: Env,
+    envs: Vec<Env>,
+}
+
+// Where an error raised in a try* goes
+struct Handler {
+    frames: usize,
+    pc: usize,
+    stack: usize,
+    env: Env,
+    envs: usize,
+}
+
+fn exception(e: MalErr) -> MalVal {
+    match e {
+        ErrMalVal(mv) => mv,
+        ErrString(s) => Str(s),
+    }
+}
+
+struct Vm {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    env: Env,
+    // the envs let* and catch* frames were entered from
+    envs: Vec<Env>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+    stack: Vec<MalVal>,
+}
+
+// Run compiled code in env, the frame of its args
+pub fn run(code: &Compiled, env: Env) -> MalRet {
+    let mut vm = Vm {
+        chunk: proto(code).chunk(&env),
+        pc: 0,
+        env,
+        envs: vec![],
+        frames: vec![],
+        handlers: vec![],
+        stack: vec![],
+    };
+    loop {
+        match vm.exec() {
+            Err(e) => vm.unwind(e)?,
+            res => return res,
+        }
+    }
+}
+
+impl Vm {
+    // Run until the outermost function returns, or there is an error
+    fn exec(&mut self) -> MalRet {
+        loop {
+            let op = &self.chunk.code[self.pc];
+            self.pc += 1;
+            match op {
+                Op::Const(v) => self.stack.push(v.clone()),
+                Op::Global(sym) => {
+                    let v = match env_get(&self.env, sym) {
+                        // unless rebound, *ENV* is the env it is evaluated in
+                        Err(_) if matches!(sym, Sym(s) if s == "*ENV*") => MalEnv(self.env.clone()),
+                        res => res?,
+                    };
+                    self.stack.push(v);
+                }
+                Op::Local(name, depth, slot) => {
+                    let v = env_get_local(&self.env, name, *depth, *slot)?;
+                    self.stack.push(v);
+                }
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Jump(to) => self.pc = *to,
+                Op::JumpIfFalse(to) => {
+                    let to = *to;
+                    if let Some(Nil) | Some(MalVal::Bool(false)) = self.stack.pop() {
+                        self.pc = to;
+                    }
+                }
+                Op::Call(argc) => {
+                    let argc = *argc;
+                    self.call(argc, false)?;
+                }
+                Op::TailCall(argc) => {
+                    let argc = *argc;
+                    if self.call(argc, true)? {
+                        if let Some(v) = self.ret() {
+                            return Ok(v);
+                        }
+                    }
+                }
+                Op::Return => {
+                    if let Some(v) = self.ret() {
+                        return Ok(v);
+                    }
+                }
+                Op::Closure(t) => {
+                    let f = MalFunc {
+                        eval,
+                        ast: t.ast.clone(),
+                        env: self.env.clone(),
+                        params: t.params.clone(),
+                        is_macro: false,
+                        meta: Rc::new(Nil),
+                        info: t.info.clone(),
+                    };
+                    self.stack.push(f);
+                }
+                Op::Def(name, doc) => {
+                    let mut v = self.stack.pop().unwrap();
+                    if let Sym(ref s) = name {
+                        v = v.named(s);
+                    }
+                    if let Some(doc) = doc {
+                        v = v.with_doc(doc);
+                    }
+                    let v = env_set(&self.env, name.clone(), v)?;
+                    self.stack.push(v);
+                }
+                Op::DefMacro(name) => {
+                    let f = make_macro(self.stack.pop().unwrap(), name)?;
+                    let f = env_set(&self.env, name.clone(), f)?;
+                    self.stack.push(f);
+                }
+                Op::Vector(n) => {
+                    let v = self.stack.split_off(self.stack.len() - n);
+                    self.stack.push(vector!(v));
+                }
+                Op::Hash(keys) => {
+                    let vals = self.stack.split_off(self.stack.len() - keys.len());
+                    let hm = keys.iter().cloned().zip(vals).collect();
+                    self.stack.push(Hash(Rc::new(hm), Rc::new(Nil)));
+                }
+                Op::EnterFrame => {
+                    let frame = env_new(Some(self.env.clone()));
+                    self.envs.push(mem::replace(&mut self.env, frame));
+                }
+                Op::Bind(pattern) => {
+                    let v = self.stack.pop().unwrap();
+                    env_destructure(&self.env, pattern, v, eval)?;
+                }
+                Op::LeaveFrame => self.env = self.envs.pop().unwrap(),
+                Op::Try(pc) => {
+                    let pc = *pc;
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        pc,
+                        stack: self.stack.len(),
+                        env: self.env.clone(),
+                        envs: self.envs.len(),
+                    });
+                }
+                Op::EndTry => {
+                    self.handlers.pop();
+                }
+                Op::Recur(target) => {
+                    let vals = self
+                        .stack
+                        .split_off(self.stack.len() - target.patterns.len());
+                    if self.envs.len() > target.depth {
+                        self.env = self.envs[target.depth].clone();
+                        self.envs.truncate(target.depth);
+                    }
+                    self.env = env_new(self.env.outer.clone());
+                    for (pattern, v) in target.patterns.iter().zip(vals) {
+                        env_destructure(&self.env, pattern, v, eval)?;
+                    }
+                    self.pc = target.start;
+                }
+                Op::Fail(msg) => return Err(ErrString(msg.clone())),
+                Op::Eval(ast) => {
+                    let v = eval(ast.clone(), self.env.clone())?;
+                    self.stack.push(v);
+                }
+            }
+        }
+    }
+
+    // Call the function below the argc args on the stack: a compiled one
+    // in a new frame, or in place of this one for a tail call. Otherwise
+    // its value is pushed, and true returned for a tail call.
+    fn call(&mut self, argc: usize, tail: bool) -> Result<bool, MalErr> {
+        let args = self.stack.split_off(self.stack.len() - argc);
+        let f = self.stack.pop().unwrap();
+        let (params, body) = match f {
+            Func(g, ..) => {
+                self.stack.push(g(args)?);
+                return Ok(tail);
+            }
+            MalFunc { .. } => f.clause(argc)?,
+            _ => return Err(ErrString("attempt to call non-function".to_string())),
+        };
+        let (code, menv) = match (body, &f) {
+            (Code(code), MalFunc { env, .. }) => (code, env),
+            _ => {
+                self.stack.push(f.apply(args)?);
+                return Ok(tail);
+            }
+        };
+        let env = env_bind_args(Some(menv.clone()), params, args, eval)?;
+        let chunk = proto(code).chunk(&env);
+        if tail {
+            self.envs.clear();
+        } else {
+            self.frames.push(Frame {
+                _depth: enter_depth()?,
+                chunk: self.chunk.clone(),
+                pc: self.pc,
+                env: self.env.clone(),
+                envs: mem::take(&mut self.envs),
+            });
+        }
+        self.chunk = chunk;
+        self.pc = 0;
+        self.env = env;
+        Ok(false)
+    }
+
+    // Return the value on the stack to the caller, or from the VM when
+    // it is the outermost function
+    fn ret(&mut self) -> Option<MalVal> {
+        let v = self.stack.pop().unwrap();
+        match self.frames.pop() {
+            Some(frame) => {
+                self.chunk = frame.chunk;
+                self.pc = frame.pc;
+                self.env = frame.env;
+                self.envs = frame.envs;
+                self.stack.push(v);
+                None
+            }
+            None => Some(v),
+        }
+    }
+
+    // Continue at the handler of the innermost try*, with the exception
+    // pushed, or fail when there is none
+    fn unwind(&mut self, e: MalErr) -> Result<(), MalErr> {
+        let h = match self.handlers.pop() {
+            Some(h) => h,
+            None => return Err(e),
+        };
+        while self.frames.len() > h.frames {
+            let frame = self.frames.pop().unwrap();
+            self.chunk = frame.chunk;
+            self.envs = frame.envs;
+        }
+        self.stack.truncate(h.stack);
+        self.envs.truncate(h.envs);
+        self.env = h.env;
+        self.pc = h.pc;
+        self.stack.push(exception(e));
+        Ok(())
+    }
+}